@@ -0,0 +1,1214 @@
+use embedded_hal::{blocking::delay::DelayUs, digital::v2::OutputPin};
+
+use crate::button::ButtonEvent;
+use crate::cv_output::{ChannelMode, ClockChannel, Prescaler};
+use crate::display::{
+    Display, Displayable, Labelled, GLYPH_C, GLYPH_D, GLYPH_E, GLYPH_H, GLYPH_N, GLYPH_P, GLYPH_R,
+    GLYPH_S, GLYPH_SMALL_B, GLYPH_SMALL_O,
+};
+use crate::eeprom::Eeprom;
+use crate::encoder::Acceleration;
+use crate::midi::{ClockOut, Message};
+use crate::presets::{self, PresetAction, PresetChoice};
+use crate::serial_cmd::{Command, Reply, Status};
+use crate::settings::{Autosave, ChannelSettings, Settings};
+use crate::shared::SharedAccess;
+use crate::state_machine::{ButtonPressed, DeviceState};
+use crate::tap_tempo::TapTempo;
+use crate::time::{TicksPerBar, TimeSignature, BPM};
+
+pub const NUM_CHANNELS: usize = 4;
+pub const MIN_BPM: BPM = BPM::new(30);
+pub const MAX_BPM: BPM = BPM::new(9999);
+
+/// Front panel input gathered during one main loop iteration.
+#[derive(Clone, Copy, Default)]
+pub struct Input {
+    pub pause_button: Option<ButtonEvent>,
+    pub encoder_button: Option<ButtonEvent>,
+    /// The pause button is currently held down.
+    pub pause_held: bool,
+    /// The encoder button is currently held down.
+    pub encoder_held: bool,
+    /// Detents turned since the last iteration, see [crate::encoder::Encoder::poll].
+    pub encoder: Option<i8>,
+}
+
+impl Input {
+    pub fn pause_clicked(&self) -> bool {
+        self.pause_button == Some(ButtonEvent::Release)
+    }
+
+    /// A click, or the second click of a double click, whose release the
+    /// [crate::button::Button] swallows, so fast clicks are not lost.
+    pub fn encoder_clicked(&self) -> bool {
+        matches!(
+            self.encoder_button,
+            Some(ButtonEvent::Release | ButtonEvent::DoubleClick)
+        )
+    }
+}
+
+/// The main loop of the module, shared by the firmware and the simulator.
+pub struct App<P: OutputPin, D: DelayUs<u8>, E: Eeprom> {
+    state: DeviceState,
+    selected_channel: i8,
+    bpm: BPM,
+    time_signature: TimeSignature,
+    bar_ticks: TicksPerBar,
+    channels: [ClockChannel<P>; NUM_CHANNELS],
+    display: Display<P, D>,
+    tap_tempo: TapTempo,
+    acceleration: Acceleration<'static>,
+    /// The pause button was held for tap tempo or a quick recall, its release
+    /// must not pause the module.
+    pause_gesture: bool,
+    /// The encoder was turned while held for fine BPM steps, its release must
+    /// not open the channel menu.
+    encoder_gesture: bool,
+    /// Sign of the last detent, repeated while pause is held in a menu.
+    last_turn: i8,
+    /// A preset was recalled while pause is held, show which.
+    recalled: bool,
+    /// Preset menu position, also the slot quick recall starts from.
+    preset: PresetChoice,
+    eeprom: E,
+    autosave: Autosave,
+    midi: ClockOut,
+}
+
+impl<P: OutputPin, D: DelayUs<u8>, E: Eeprom> App<P, D, E> {
+    /// Starts with the settings stored in `eeprom`. `pins` holds the
+    /// `(led, output)` pair of every channel.
+    pub fn new(mut eeprom: E, pins: [(P, P); NUM_CHANNELS], display: Display<P, D>) -> Self {
+        let settings = &Settings::load(&mut eeprom);
+        let bpm = settings.bpm;
+        let ticks_per_bar = TicksPerBar::new(bpm, settings.time_signature).ticks;
+        let mut midi = ClockOut::new();
+        midi.set_time_signature(settings.time_signature);
+        let mut channel_settings = settings.channels.iter();
+        let mut seed = 0;
+        App {
+            state: DeviceState::Running,
+            selected_channel: 0,
+            bpm,
+            time_signature: settings.time_signature,
+            bar_ticks: TicksPerBar {
+                ticks: ticks_per_bar,
+            },
+            channels: pins.map(|(led_pin, output_pin)| {
+                let settings = channel_settings.next().unwrap();
+                let mut channel = ClockChannel::new(
+                    led_pin,
+                    output_pin,
+                    Prescaler::new(settings.numerator, settings.denominator),
+                    ticks_per_bar,
+                );
+                channel.set_gate_mode(settings.gate_mode);
+                channel.set_mode(settings.mode);
+                channel.set_swing(settings.swing);
+                channel.set_euclid(settings.euclid);
+                channel.set_probability(settings.probability);
+                channel.set_offset(settings.offset);
+                channel.set_ratchet(settings.ratchet);
+                // every channel draws its own sequence
+                seed += 1;
+                channel.set_seed(seed);
+                channel
+            }),
+            display,
+            tap_tempo: TapTempo::new(),
+            acceleration: Acceleration::default(),
+            pause_gesture: false,
+            encoder_gesture: false,
+            last_turn: 1,
+            recalled: false,
+            preset: PresetChoice::new(PresetAction::Load, 0),
+            eeprom,
+            autosave: Autosave::new(*settings),
+            midi,
+        }
+    }
+
+    pub fn state(&self) -> DeviceState {
+        self.state
+    }
+
+    pub fn bpm(&self) -> BPM {
+        self.bpm
+    }
+
+    pub fn time_signature(&self) -> TimeSignature {
+        self.time_signature
+    }
+
+    pub fn selected_channel(&self) -> usize {
+        self.selected_channel as usize
+    }
+
+    /// Snapshot of everything edited on the front panel.
+    pub fn settings(&self) -> Settings {
+        let mut settings = Settings {
+            bpm: self.bpm,
+            time_signature: self.time_signature,
+            ..Settings::default()
+        };
+        for (settings, channel) in settings.channels.iter_mut().zip(&self.channels) {
+            *settings = ChannelSettings {
+                numerator: channel.get_numerator(),
+                denominator: channel.get_denominator(),
+                gate_mode: channel.get_gate_mode(),
+                mode: channel.get_mode(),
+                swing: channel.get_swing(),
+                euclid: channel.get_euclid(),
+                probability: channel.get_probability(),
+                offset: channel.get_offset(),
+                ratchet: channel.get_ratchet(),
+            };
+        }
+        settings
+    }
+
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.set_bpm(settings.bpm);
+        self.set_time_signature(settings.time_signature);
+        for (channel, settings) in self.channels.iter_mut().zip(&settings.channels) {
+            channel.set_prescaler(
+                Prescaler::new(settings.numerator, settings.denominator),
+                self.bar_ticks.ticks,
+            );
+            channel.set_gate_mode(settings.gate_mode);
+            channel.set_mode(settings.mode);
+            channel.set_swing(settings.swing);
+            channel.set_euclid(settings.euclid);
+            channel.set_probability(settings.probability);
+            channel.set_offset(settings.offset);
+            channel.set_ratchet(settings.ratchet);
+        }
+        self.reset_channels();
+    }
+
+    /// The next MIDI real-time byte to send, call until it returns `None`.
+    pub fn midi_byte(&mut self) -> Option<u8> {
+        self.midi.next_byte()
+    }
+
+    /// Follows a MIDI message received on the serial port, see
+    /// [crate::midi::Parser]. Start and continue only leave the pause, not
+    /// the menus.
+    pub fn receive_midi(&mut self, message: Message, shared: &mut impl SharedAccess) {
+        match message {
+            Message::TimingClock => shared.with_shared(|shared| shared.midi_clock_edge()),
+            Message::Start => {
+                // the first timing clock after start is the downbeat
+                shared.with_shared(|shared| {
+                    shared.reset_edge();
+                    shared.midi_clock.set_position(0);
+                });
+                if self.state == DeviceState::Paused {
+                    self.state = DeviceState::Running;
+                }
+            }
+            Message::Continue => {
+                if self.state == DeviceState::Paused {
+                    self.state = DeviceState::Running;
+                }
+            }
+            Message::Stop => {
+                if self.state == DeviceState::Running {
+                    self.state = DeviceState::Paused;
+                }
+            }
+            Message::SongPosition(position) => shared.with_shared(|shared| {
+                shared
+                    .midi_clock
+                    .set_position(Message::bar_clock(position, self.time_signature))
+            }),
+        }
+    }
+
+    /// Carries out a command received over the serial port, see
+    /// [crate::serial_cmd].
+    pub fn execute(&mut self, command: Command, shared: &mut impl SharedAccess) -> Reply {
+        match command {
+            Command::Bpm(bpm) => self.set_bpm(bpm),
+            Command::Div {
+                channel,
+                denominator,
+            } => {
+                let numerator = self.channels[channel].get_numerator();
+                self.channels[channel]
+                    .set_prescaler(Prescaler::new(numerator, denominator), self.bar_ticks.ticks);
+            }
+            Command::Num { channel, numerator } => {
+                let denominator = self.channels[channel].get_denominator();
+                self.channels[channel]
+                    .set_prescaler(Prescaler::new(numerator, denominator), self.bar_ticks.ticks);
+            }
+            Command::Pause => self.state = DeviceState::Paused,
+            Command::Run => {
+                if self.state != DeviceState::Paused {
+                    // leaving a menu, like the pause button does
+                    self.reset_channels();
+                }
+                self.state = DeviceState::Running;
+            }
+            Command::Reset => shared.with_shared(|shared| shared.reset_edge()),
+            Command::GetState => {
+                let settings = self.settings();
+                return Reply::Status(Status {
+                    state: self.state,
+                    bpm: settings.bpm,
+                    ratios: settings
+                        .channels
+                        .map(|channel| (channel.numerator, channel.denominator)),
+                });
+            }
+        }
+        Reply::Ok
+    }
+
+    /// Runs one iteration of the main loop.
+    pub fn step(&mut self, input: Input, shared: &mut impl SharedAccess) {
+        let input = self.repeat_turn(input);
+        let timestamp = shared.with_shared(|shared| shared.timestamp);
+        match self.state {
+            DeviceState::Running => {
+                let external_bpm = shared.with_shared(|shared| {
+                    let now = shared.timestamp;
+                    shared
+                        .external_clock
+                        .bpm(now)
+                        .or_else(|| shared.midi_clock.bpm(now))
+                });
+                match external_bpm {
+                    Some(bpm) => {
+                        let bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+                        if bpm != self.bpm {
+                            self.set_bpm(bpm);
+                        }
+                    }
+                    None => match (input.encoder, input.pause_held, input.encoder_held) {
+                        (Some(change), false, true) => {
+                            // fine: hold the encoder button and turn for tenths
+                            self.encoder_gesture = true;
+                            self.change_bpm(change as i16);
+                        }
+                        (Some(change), false, false) => {
+                            let change = self.acceleration.accelerate(change, timestamp);
+                            self.change_bpm(change * 10);
+                        }
+                        _ => {}
+                    },
+                }
+                if let (Some(change), true) = (input.encoder, input.pause_held) {
+                    // quick recall: hold pause and turn to the next saved preset
+                    self.pause_gesture = true;
+                    self.recalled = true;
+                    let slot = self.preset.slot;
+                    if let Some((slot, settings)) =
+                        presets::next_saved(&mut self.eeprom, slot, change)
+                    {
+                        self.load_preset(slot, &settings, shared);
+                    }
+                }
+                let following = external_bpm.is_some();
+                shared.with_shared(|shared| {
+                    if core::mem::take(&mut shared.reset_pending) {
+                        self.reset_channels();
+                        self.midi.restart();
+                    }
+                    self.update_channels(&mut shared.ticks, following)
+                });
+                if self.recalled && input.pause_held {
+                    self.display
+                        .update(PresetChoice::new(PresetAction::Load, self.preset.slot));
+                } else {
+                    self.recalled = false;
+                    self.display.update(self.bpm);
+                }
+                let encoder_down = matches!(
+                    input.encoder_button,
+                    Some(ButtonEvent::Press | ButtonEvent::DoubleClick)
+                );
+                if input.pause_held && encoder_down {
+                    // tap tempo: hold pause and tap the encoder button
+                    self.pause_gesture = true;
+                    if let Some(bpm) = self.tap_tempo.tap(timestamp) {
+                        if !following {
+                            self.set_bpm(bpm.clamp(MIN_BPM, MAX_BPM));
+                        }
+                    }
+                } else if input.encoder_clicked() && !input.pause_held && !self.encoder_gesture {
+                    self.show_selected_channel();
+                    self.state = self.state.transition(ButtonPressed::EncoderButton);
+                }
+                if input.pause_clicked() && !core::mem::take(&mut self.pause_gesture) {
+                    self.state = self.state.transition(ButtonPressed::PauseButton);
+                }
+            }
+
+            DeviceState::PresetState => {
+                if let Some(change) = input.encoder {
+                    self.preset = self.preset.step(change);
+                }
+                self.display.update(self.preset);
+                if input.encoder_clicked() {
+                    let slot = self.preset.slot;
+                    match self.preset.action {
+                        PresetAction::Load => {
+                            if let Some(settings) = presets::load(&mut self.eeprom, slot) {
+                                self.load_preset(slot, &settings, shared);
+                            }
+                        }
+                        PresetAction::Save => {
+                            let settings = self.settings();
+                            presets::save(&mut self.autosave, slot, &settings);
+                        }
+                    }
+                    self.state = self.state.transition(ButtonPressed::EncoderButton);
+                }
+                if input.pause_clicked() {
+                    self.state = self.state.transition(ButtonPressed::PauseButton);
+                }
+            }
+
+            DeviceState::Paused => {
+                if input.pause_clicked() {
+                    self.state = self.state.transition(ButtonPressed::PauseButton);
+                }
+                if input.encoder_clicked() {
+                    self.state = self.state.transition(ButtonPressed::EncoderButton);
+                }
+                self.display.update(self.bpm);
+            }
+
+            DeviceState::SettingPpqnState => {
+                let ppqn = shared.with_shared(|shared| {
+                    if let Some(change) = input.encoder {
+                        shared.external_clock.update_ppqn(change);
+                    }
+                    shared.external_clock.ppqn()
+                });
+                self.display.update(Labelled::new(GLYPH_C, ppqn as u16));
+                if input.encoder_clicked() {
+                    self.state = self.state.transition(ButtonPressed::EncoderButton);
+                }
+                if input.pause_clicked() {
+                    self.state = self.state.transition(ButtonPressed::PauseButton);
+                }
+            }
+
+            DeviceState::SettingBeatsState => {
+                if let Some(change) = input.encoder {
+                    self.set_time_signature(self.time_signature.step_beats(change));
+                }
+                self.display.update(self.time_signature);
+                if input.encoder_clicked() {
+                    self.state = self.state.transition(ButtonPressed::EncoderButton);
+                }
+                if input.pause_clicked() {
+                    self.state = self.state.transition(ButtonPressed::PauseButton);
+                }
+            }
+
+            DeviceState::SettingBeatUnitState => {
+                if let Some(change) = input.encoder {
+                    self.set_time_signature(self.time_signature.step_unit(change));
+                }
+                self.display.update(self.time_signature);
+                if input.encoder_clicked() {
+                    self.show_selected_channel();
+                    self.state = self.state.transition(ButtonPressed::EncoderButton);
+                }
+                if input.pause_clicked() {
+                    self.state = self.state.transition(ButtonPressed::PauseButton);
+                }
+            }
+
+            DeviceState::SelectingChannel => {
+                if let Some(change) = input.encoder {
+                    self.selected_channel =
+                        (self.selected_channel + change).rem_euclid(NUM_CHANNELS as i8);
+                    self.show_selected_channel();
+                }
+                if input.encoder_clicked() {
+                    self.show_selected_channel();
+                    self.state = self.state.transition(ButtonPressed::EncoderButton);
+                }
+                if input.pause_clicked() {
+                    self.reset_channels();
+                    self.state = self.state.transition(ButtonPressed::PauseButton);
+                }
+                self.display.update(self.bpm);
+            }
+
+            DeviceState::SettingDivisionState => {
+                let bar_ticks = self.bar_ticks.ticks;
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_denominator(change, bar_ticks),
+                    |channel| Labelled::new(GLYPH_D, channel.get_denominator()),
+                );
+            }
+
+            DeviceState::SettingNumeratorState => {
+                let bar_ticks = self.bar_ticks.ticks;
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_numerator(change, bar_ticks),
+                    |channel| Labelled::new(GLYPH_N, channel.get_numerator()),
+                );
+            }
+
+            DeviceState::SettingGateState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_gate_mode(change),
+                    |channel| channel.get_gate_mode(),
+                );
+            }
+
+            DeviceState::SettingModeState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_mode(change),
+                    |channel| channel.get_mode(),
+                );
+            }
+
+            DeviceState::SettingStepsState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_steps(change),
+                    |channel| Labelled::new(GLYPH_E, channel.get_euclid().steps() as u16),
+                );
+            }
+
+            DeviceState::SettingPulsesState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_pulses(change),
+                    |channel| Labelled::new(GLYPH_H, channel.get_euclid().pulses() as u16),
+                );
+            }
+
+            DeviceState::SettingRotationState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_rotation(change),
+                    |channel| Labelled::new(GLYPH_R, channel.get_euclid().rotation() as u16),
+                );
+            }
+
+            DeviceState::SettingRatchetState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_ratchet_count(change),
+                    |channel| Labelled::new(GLYPH_SMALL_B, channel.get_ratchet().count() as u16),
+                );
+            }
+
+            DeviceState::SettingRatchetEveryState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_ratchet_every(change),
+                    |channel| Labelled::new(GLYPH_E, channel.get_ratchet().every() as u16),
+                );
+            }
+
+            DeviceState::SettingSwingState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_swing(change),
+                    |channel| Labelled::new(GLYPH_S, channel.get_swing() as u16),
+                );
+            }
+
+            DeviceState::SettingProbabilityState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_probability(change),
+                    |channel| Labelled::new(GLYPH_P, channel.get_probability() as u16),
+                );
+            }
+
+            DeviceState::SettingOffsetState => {
+                self.channel_setting(
+                    input,
+                    |channel, change| channel.update_offset(change),
+                    |channel| Labelled::new(GLYPH_SMALL_O, channel.get_offset() as u16),
+                );
+            }
+        }
+
+        if input.encoder_button == Some(ButtonEvent::LongPress)
+            && !input.pause_held
+            && !self.encoder_gesture
+        {
+            // enter the preset menu, or leave the menus without restarting
+            // the channels
+            self.state = self.state.transition(ButtonPressed::EncoderLongPress);
+        }
+        if input.pause_button == Some(ButtonEvent::DoubleClick) {
+            // restart from the top of the bar, like the reset input
+            shared.with_shared(|shared| shared.reset_edge());
+            self.state = self.state.transition(ButtonPressed::PauseDoubleClick);
+        }
+        if !input.encoder_held {
+            self.encoder_gesture = false;
+        }
+
+        // the settings menus reached from pause keep the module paused
+        let paused = matches!(
+            self.state,
+            DeviceState::Paused
+                | DeviceState::SettingPpqnState
+                | DeviceState::SettingBeatsState
+                | DeviceState::SettingBeatUnitState
+        );
+        self.midi.set_running(!paused);
+        let time_signature = self.time_signature;
+        shared.with_shared(|shared| shared.set_time_signature(time_signature));
+        self.autosave
+            .poll(self.settings(), timestamp, &mut self.eeprom);
+    }
+
+    /// Holding pause in a menu keeps turning the encoder the way it last
+    /// turned, a detent for every [ButtonEvent::Hold] repeat.
+    fn repeat_turn(&mut self, mut input: Input) -> Input {
+        if let Some(change) = input.encoder {
+            self.last_turn = change.signum();
+        }
+        let menu = !matches!(self.state, DeviceState::Running | DeviceState::Paused);
+        if menu && matches!(input.pause_button, Some(ButtonEvent::Hold(_))) {
+            input.encoder.get_or_insert(self.last_turn);
+        }
+        input
+    }
+
+    /// Switches to a preset from the top of the bar.
+    fn load_preset(&mut self, slot: u8, settings: &Settings, shared: &mut impl SharedAccess) {
+        self.apply_settings(settings);
+        self.preset = PresetChoice::new(PresetAction::Load, slot);
+        shared.with_shared(|shared| shared.reset_edge());
+    }
+
+    /// Changes the tempo by `change` tenths of a BPM.
+    fn change_bpm(&mut self, change: i16) {
+        let bpm = (self.bpm + change).clamp(MIN_BPM, MAX_BPM);
+        if bpm != self.bpm {
+            self.set_bpm(bpm);
+        }
+    }
+
+    fn set_bpm(&mut self, bpm: BPM) {
+        self.bpm = bpm;
+        self.update_bar_ticks();
+    }
+
+    /// Bars of `time_signature` restart the channels, the clock inputs pick
+    /// it up at the end of [Self::step].
+    fn set_time_signature(&mut self, time_signature: TimeSignature) {
+        self.time_signature = time_signature;
+        self.midi.set_time_signature(time_signature);
+        self.update_bar_ticks();
+    }
+
+    fn update_bar_ticks(&mut self) {
+        self.bar_ticks = TicksPerBar::new(self.bpm, self.time_signature);
+        for channel in self.channels.iter_mut() {
+            channel.calculate_threshold(self.bar_ticks.ticks);
+        }
+    }
+
+    /// While `following` the clock input, bars are restarted by its edges
+    /// instead, see [crate::shared::Shared::clock_edge]. Should the input run
+    /// late, the channels wait at the end of the bar.
+    fn update_channels(&mut self, ticks: &mut u32, following: bool) {
+        let mut position = *ticks;
+        if *ticks >= self.bar_ticks.ticks {
+            if following {
+                position = self.bar_ticks.ticks - 1;
+            } else {
+                *ticks = 0;
+                position = 0;
+                for channel in self.channels.iter_mut() {
+                    channel.next_bar();
+                }
+            }
+        }
+        for channel in self.channels.iter_mut() {
+            channel.update(position);
+        }
+        self.midi.update(position, self.bar_ticks.ticks);
+    }
+
+    fn reset_channels(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.reset_threshold();
+        }
+    }
+
+    /// One channel setting menu: turning the encoder applies `update` to the
+    /// selected channel and `show` picks what the display shows of it. A
+    /// click moves on to the next menu, pause leaves the menus.
+    fn channel_setting<V: Displayable>(
+        &mut self,
+        input: Input,
+        update: impl FnOnce(&mut ClockChannel<P>, i8),
+        show: impl FnOnce(&ClockChannel<P>) -> V,
+    ) {
+        let channel = &mut self.channels[self.selected_channel as usize];
+        if let Some(change) = input.encoder {
+            channel.set_led(true);
+            update(channel, change);
+        }
+        self.display.update(show(channel));
+        if input.encoder_clicked() {
+            self.show_selected_channel();
+            self.next_channel_menu();
+        }
+        if input.pause_clicked() {
+            self.reset_channels();
+            self.state = self.state.transition(ButtonPressed::PauseButton);
+        }
+    }
+
+    /// Moves on to the next channel menu, skipping those of the modes the
+    /// selected channel is not in.
+    fn next_channel_menu(&mut self) {
+        let mode = self.channels[self.selected_channel as usize].get_mode();
+        loop {
+            self.state = self.state.transition(ButtonPressed::EncoderButton);
+            let applies = match self.state {
+                DeviceState::SettingStepsState
+                | DeviceState::SettingPulsesState
+                | DeviceState::SettingRotationState => mode == ChannelMode::Euclid,
+                DeviceState::SettingRatchetState | DeviceState::SettingRatchetEveryState => {
+                    mode == ChannelMode::Ratchet
+                }
+                _ => true,
+            };
+            if applies {
+                break;
+            }
+        }
+    }
+
+    fn show_selected_channel(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.set_led(false);
+        }
+        self.channels[self.selected_channel as usize].set_led(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cv_output::Ratchet;
+    use crate::euclid::Euclid;
+    use crate::midi;
+    use crate::mock::{MockEeprom, MockPin, NoDelay};
+    use crate::shared::Shared;
+
+    fn app() -> (App<MockPin, NoDelay, MockEeprom>, [MockPin; NUM_CHANNELS]) {
+        let outputs = [(); NUM_CHANNELS].map(|_| MockPin::new());
+        let display = Display::new(MockPin::new(), MockPin::new(), MockPin::new(), NoDelay);
+        let pins = outputs.clone().map(|output| (MockPin::new(), output));
+        (App::new(MockEeprom::new(), pins, display), outputs)
+    }
+
+    #[test]
+    fn encoder_changes_bpm_while_running() {
+        let (mut app, _) = app();
+        let input = Input {
+            encoder: Some(1),
+            ..Default::default()
+        };
+        app.step(input, &mut Shared::new());
+        assert_eq!(app.bpm(), BPM::new(121));
+    }
+
+    #[test]
+    fn fast_spin_accelerates_bpm() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let input = Input {
+            encoder: Some(1),
+            ..Default::default()
+        };
+        for _ in 0..11 {
+            app.step(input, &mut shared);
+            for _ in 0..50 {
+                shared.timer_tick();
+            }
+        }
+        assert_eq!(app.bpm(), BPM::new(121 + 10 * 25));
+        let input = Input {
+            encoder: Some(-1),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            app.step(input, &mut shared);
+        }
+        assert_eq!(app.bpm(), MIN_BPM);
+    }
+
+    #[test]
+    fn turning_while_the_encoder_is_held_steps_tenths() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let turn = Input {
+            encoder_held: true,
+            encoder: Some(1),
+            ..Default::default()
+        };
+        for _ in 0..5 {
+            app.step(turn, &mut shared);
+        }
+        assert_eq!(app.bpm(), BPM::from_tenths(1205));
+        let release = Input {
+            encoder_button: Some(ButtonEvent::Release),
+            ..Default::default()
+        };
+        app.step(release, &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+        app.step(release, &mut shared);
+        assert_eq!(app.state(), DeviceState::SelectingChannel);
+    }
+
+    #[test]
+    fn channel_selection_wraps_several_detents() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let click = Input {
+            encoder_button: Some(ButtonEvent::Release),
+            ..Default::default()
+        };
+        let turn = |change| Input {
+            encoder: Some(change),
+            ..Default::default()
+        };
+        app.step(click, &mut shared);
+        assert_eq!(app.state(), DeviceState::SelectingChannel);
+        app.step(turn(3), &mut shared);
+        assert_eq!(app.selected_channel(), 3);
+        app.step(turn(2), &mut shared);
+        assert_eq!(app.selected_channel(), 1);
+        app.step(turn(-6), &mut shared);
+        assert_eq!(app.selected_channel(), 3);
+    }
+
+    #[test]
+    fn holding_pause_repeats_the_last_turn_in_menus() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let input = |encoder_button, pause_button, encoder| Input {
+            encoder_button,
+            pause_button,
+            encoder,
+            ..Default::default()
+        };
+        let click = input(Some(ButtonEvent::Release), None, None);
+        app.step(click, &mut shared);
+        app.step(click, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingDivisionState);
+        app.step(input(None, None, Some(-1)), &mut shared);
+        assert_eq!(app.settings().channels[0].denominator, 1);
+        app.step(input(None, Some(ButtonEvent::LongPress), None), &mut shared);
+        for repeat in 1..=3 {
+            let hold = input(None, Some(ButtonEvent::Hold(repeat)), None);
+            app.step(hold, &mut shared);
+        }
+        assert_eq!(app.settings().channels[0].denominator, 126);
+        assert_eq!(app.state(), DeviceState::SettingDivisionState);
+    }
+
+    #[test]
+    fn fast_encoder_clicks_step_through_the_menus() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let mut button = crate::button::Button::default();
+        // four clicks 100 ms apart, the second and fourth are double clicks
+        for _ in 0..4 {
+            for pressed in [true, false] {
+                for _ in 0..500 {
+                    shared.timer_tick();
+                    let now = shared.timestamp;
+                    let input = Input {
+                        encoder_button: button.update(pressed, now),
+                        encoder_held: button.is_pressed(),
+                        ..Default::default()
+                    };
+                    app.step(input, &mut shared);
+                }
+            }
+        }
+        assert_eq!(app.state(), DeviceState::SettingGateState);
+    }
+
+    #[test]
+    fn tapping_while_pause_is_held_sets_bpm() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let tap = Input {
+            pause_held: true,
+            encoder_button: Some(ButtonEvent::Press),
+            ..Default::default()
+        };
+        // 100 BPM is 6000 ticks per beat
+        for _ in 0..4 {
+            app.step(tap, &mut shared);
+            for _ in 0..6000 {
+                shared.timer_tick();
+            }
+        }
+        assert_eq!(app.bpm(), BPM::new(100));
+        assert_eq!(app.state(), DeviceState::Running);
+        let release = Input {
+            pause_button: Some(ButtonEvent::Release),
+            ..Default::default()
+        };
+        app.step(release, &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+        app.step(release, &mut shared);
+        assert_eq!(app.state(), DeviceState::Paused);
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let (mut app, _) = app();
+        let mut settings = Settings {
+            bpm: BPM::new(93),
+            time_signature: TimeSignature::try_new(5, 4).unwrap(),
+            ..Settings::default()
+        };
+        settings.channels[2].numerator = 3;
+        settings.channels[2].denominator = 7;
+        settings.channels[2].euclid = Euclid::new(5, 2, 1);
+        settings.channels[2].probability = 40;
+        settings.channels[3].offset = 50;
+        settings.channels[3].ratchet = Ratchet::new(4, 2);
+        app.apply_settings(&settings);
+        assert_eq!(app.settings(), settings);
+    }
+
+    #[test]
+    fn bar_end_restarts_ticks_and_channels() {
+        let (mut app, outputs) = app();
+        let mut shared = Shared::new();
+        shared.ticks = TicksPerBar::from(BPM::new(120)).ticks;
+        app.step(Input::default(), &mut shared);
+        assert_eq!(shared.ticks, 0);
+        assert!(outputs.iter().all(|output| output.is_high()));
+    }
+
+    #[test]
+    fn time_signature_is_edited_while_paused() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let click = |pause_button, encoder_button| Input {
+            pause_button,
+            encoder_button,
+            ..Default::default()
+        };
+        let press = click(None, Some(ButtonEvent::Release));
+        let turn = |change| Input {
+            encoder: Some(change),
+            ..Default::default()
+        };
+        app.step(click(Some(ButtonEvent::Release), None), &mut shared);
+        app.step(press, &mut shared);
+        app.step(press, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingBeatsState);
+        app.step(turn(5), &mut shared);
+        app.step(press, &mut shared);
+        app.step(turn(1), &mut shared);
+        assert_eq!(app.time_signature(), TimeSignature::try_new(7, 8).unwrap());
+        app.step(click(Some(ButtonEvent::Release), None), &mut shared);
+        app.step(click(Some(ButtonEvent::Release), None), &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+        // 7/8 at 120 BPM
+        shared.ticks = 17_499;
+        app.step(Input::default(), &mut shared);
+        assert_ne!(shared.ticks, 0);
+        shared.ticks = 17_500;
+        app.step(Input::default(), &mut shared);
+        assert_eq!(shared.ticks, 0);
+    }
+
+    #[test]
+    fn reset_input_restarts_the_bar() {
+        let (mut app, outputs) = app();
+        let mut shared = Shared::new();
+        for _ in 0..TicksPerBar::from(BPM::new(120)).ticks / 4 + 1 {
+            shared.timer_tick();
+            app.step(Input::default(), &mut shared);
+        }
+        assert!(!outputs[0].is_high());
+        shared.reset_edge();
+        app.step(Input::default(), &mut shared);
+        assert!(!shared.reset_pending);
+        assert!(outputs.iter().all(|output| output.is_high()));
+    }
+
+    #[test]
+    fn follows_the_clock_input() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        // 100 BPM at 24 PPQN
+        let interval = 60 * crate::time::TICK_RATE / 100 / 24;
+        for _ in 0..(2 * 24 + 1) {
+            for _ in 0..interval {
+                shared.timer_tick();
+            }
+            shared.clock_edge();
+            app.step(Input::default(), &mut shared);
+        }
+        assert_eq!(app.bpm(), BPM::new(100));
+        // the bar waits for the clock input instead of wrapping
+        shared.ticks = TicksPerBar::from(app.bpm()).ticks + 10;
+        app.step(Input::default(), &mut shared);
+        assert_ne!(shared.ticks, 0);
+    }
+
+    #[test]
+    fn encoder_button_selects_then_edits_division() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let press = Input {
+            encoder_button: Some(ButtonEvent::Release),
+            ..Default::default()
+        };
+        let turn = Input {
+            encoder: Some(1),
+            ..Default::default()
+        };
+        app.step(press, &mut shared);
+        app.step(turn, &mut shared);
+        assert_eq!(app.selected_channel(), 1);
+        app.step(press, &mut shared);
+        app.step(turn, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingDivisionState);
+        assert_eq!(app.channels[1].get_denominator(), 5);
+        app.step(press, &mut shared);
+        app.step(turn, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingNumeratorState);
+        assert_eq!(app.channels[1].get_numerator(), 2);
+        let long_press = Input {
+            encoder_button: Some(ButtonEvent::LongPress),
+            ..Default::default()
+        };
+        app.step(long_press, &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+    }
+
+    #[test]
+    fn mode_menus_are_shown_only_in_their_mode() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let press = Input {
+            encoder_button: Some(ButtonEvent::Release),
+            ..Default::default()
+        };
+        let turn = |change| Input {
+            encoder: Some(change),
+            ..Default::default()
+        };
+        for _ in 0..5 {
+            app.step(press, &mut shared);
+        }
+        assert_eq!(app.state(), DeviceState::SettingModeState);
+        app.step(press, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingSwingState);
+        for _ in 0..7 {
+            app.step(press, &mut shared);
+        }
+        app.step(turn(1), &mut shared);
+        app.step(turn(1), &mut shared);
+        assert_eq!(app.channels[0].get_mode(), ChannelMode::Euclid);
+        app.step(press, &mut shared);
+        app.step(turn(8), &mut shared);
+        app.step(press, &mut shared);
+        app.step(turn(2), &mut shared);
+        app.step(press, &mut shared);
+        app.step(turn(-1), &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingRotationState);
+        app.step(press, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingSwingState);
+        for _ in 0..7 {
+            app.step(press, &mut shared);
+        }
+        app.step(turn(1), &mut shared);
+        assert_eq!(app.channels[0].get_mode(), ChannelMode::Ratchet);
+        app.step(press, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingRatchetState);
+        app.step(press, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingRatchetEveryState);
+        app.step(press, &mut shared);
+        assert_eq!(app.state(), DeviceState::SettingSwingState);
+        assert_eq!(app.settings().channels[0].euclid, Euclid::new(16, 5, 15));
+    }
+
+    #[test]
+    fn presets_save_and_quick_recall() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let long_press = Input {
+            encoder_button: Some(ButtonEvent::LongPress),
+            ..Default::default()
+        };
+        let turn = |change| Input {
+            encoder: Some(change),
+            ..Default::default()
+        };
+        let click = Input {
+            encoder_button: Some(ButtonEvent::Release),
+            ..Default::default()
+        };
+        app.step(long_press, &mut shared);
+        assert_eq!(app.state(), DeviceState::PresetState);
+        // past the load slots to saving slot 3
+        app.step(turn(presets::PRESET_SLOTS as i8 + 2), &mut shared);
+        let writes = app.eeprom.writes();
+        app.step(click, &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+        // written a byte per step, the main loop never waits for the EEPROM
+        app.step(Input::default(), &mut shared);
+        assert_eq!(app.eeprom.writes(), writes + 1);
+        while app.autosave.is_writing() {
+            app.step(Input::default(), &mut shared);
+        }
+        assert_eq!(presets::load(&mut app.eeprom, 2), Some(app.settings()));
+
+        app.step(turn(5), &mut shared);
+        assert_eq!(app.bpm(), BPM::new(125));
+        let recall = Input {
+            pause_held: true,
+            encoder: Some(1),
+            ..Default::default()
+        };
+        app.step(recall, &mut shared);
+        assert_eq!(app.bpm(), BPM::new(120));
+        let release = Input {
+            pause_button: Some(ButtonEvent::Release),
+            ..Default::default()
+        };
+        app.step(release, &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+    }
+
+    #[test]
+    fn settings_are_saved_once_settled() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let turn = Input {
+            encoder: Some(-1),
+            ..Default::default()
+        };
+        app.step(turn, &mut shared);
+        for _ in 0..crate::settings::SETTLE_TICKS + 100 {
+            shared.timer_tick();
+            app.step(Input::default(), &mut shared);
+        }
+        assert_eq!(Settings::load(&mut app.eeprom).bpm, BPM::new(119));
+    }
+
+    #[test]
+    fn serial_commands_configure_the_module() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let ok = |app: &mut App<_, _, _>, shared: &mut Shared, line| {
+            let command = crate::serial_cmd::parse(line).unwrap();
+            assert_eq!(app.execute(command, shared), Reply::Ok);
+        };
+        ok(&mut app, &mut shared, "bpm 128.5");
+        ok(&mut app, &mut shared, "div 2 16");
+        ok(&mut app, &mut shared, "num 2 3");
+        ok(&mut app, &mut shared, "pause");
+        assert_eq!(
+            app.execute(Command::GetState, &mut shared),
+            Reply::Status(Status {
+                state: DeviceState::Paused,
+                bpm: BPM::from_tenths(1285),
+                ratios: [(1, 2), (3, 16), (1, 6), (1, 8)],
+            })
+        );
+        ok(&mut app, &mut shared, "run");
+        assert_eq!(app.state(), DeviceState::Running);
+        shared.ticks = 1000;
+        ok(&mut app, &mut shared, "reset");
+        assert!(shared.reset_pending);
+        assert_eq!(shared.ticks, 0);
+    }
+
+    #[test]
+    fn midi_follows_pause_and_reset() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let mut sent = Vec::new();
+        let mut run = |app: &mut App<_, _, _>, shared: &mut Shared, input: Input| {
+            shared.timer_tick();
+            app.step(input, shared);
+            sent.extend(core::iter::from_fn(|| app.midi_byte()));
+            core::mem::take(&mut sent)
+        };
+        let bytes = run(&mut app, &mut shared, Input::default());
+        assert_eq!(bytes, [midi::START, midi::TIMING_CLOCK]);
+        let pause = Input {
+            pause_button: Some(ButtonEvent::Release),
+            ..Input::default()
+        };
+        assert_eq!(run(&mut app, &mut shared, pause), [midi::STOP]);
+        assert_eq!(run(&mut app, &mut shared, pause), [midi::CONTINUE]);
+        shared.reset_edge();
+        let bytes = run(&mut app, &mut shared, Input::default());
+        assert_eq!(bytes, [midi::START, midi::TIMING_CLOCK]);
+    }
+
+    #[test]
+    fn follows_midi_clock_and_transport() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        app.receive_midi(Message::Stop, &mut shared);
+        assert_eq!(app.state(), DeviceState::Paused);
+        app.receive_midi(Message::Start, &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+        // 90 BPM is a timing clock every 277.8 ticks, the first starts the bar
+        for clock in 0..2 * 24 {
+            while shared.timestamp < clock * 10_000 * 60 / (90 * 24) {
+                shared.timer_tick();
+                app.step(Input::default(), &mut shared);
+            }
+            app.receive_midi(Message::TimingClock, &mut shared);
+            if clock == 0 {
+                assert_eq!(shared.ticks, 0);
+            }
+        }
+        app.step(Input::default(), &mut shared);
+        assert_eq!(app.bpm(), BPM::new(90));
+        // a song position a beat into the bar
+        app.receive_midi(Message::SongPosition(4), &mut shared);
+        app.receive_midi(Message::TimingClock, &mut shared);
+        assert_ne!(shared.ticks, 0);
+    }
+
+    #[test]
+    fn pause_double_click_restarts_the_bar() {
+        let (mut app, _) = app();
+        let mut shared = Shared::new();
+        let click = |event| Input {
+            pause_button: Some(event),
+            ..Default::default()
+        };
+        app.step(click(ButtonEvent::Release), &mut shared);
+        assert_eq!(app.state(), DeviceState::Paused);
+        shared.ticks = 1234;
+        app.step(click(ButtonEvent::DoubleClick), &mut shared);
+        assert_eq!(app.state(), DeviceState::Running);
+        assert_eq!(shared.ticks, 0);
+        assert!(shared.reset_pending);
+    }
+}
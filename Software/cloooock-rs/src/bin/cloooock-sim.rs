@@ -0,0 +1,606 @@
+//! Desktop simulator of the whole module.
+//!
+//! Runs the firmware main loop ([App::step]) against virtual interrupts and a
+//! scripted front panel, and prints every output edge, LED change,
+//! 7-segment frame, state change and MIDI start, stop or continue together
+//! with the tick it happened at.
+//!
+//! ```text
+//! cargo run --features sim --bin cloooock-sim --target x86_64-unknown-linux-gnu -- script.txt
+//! ```
+//!
+//! The script is read from the given file or stdin, one command per line and
+//! `#` starting a comment:
+//!
+//! ```text
+//! wait <ticks>            let time pass, `wait 250ms` for milliseconds
+//! turn <detents>          turn the encoder, negative is counter clockwise.
+//!                         The detents come as fast as the ADC samples, so
+//!                         BPM changes are accelerated
+//! press pause|encoder     press and release a button, pressing again
+//!                         right away is a double click
+//! hold pause|encoder      press a button and keep it down, `wait` for a
+//!                         long press. Tap tempo is `hold pause` followed
+//!                         by `press encoder` taps
+//! release pause|encoder   let go of a held button
+//! clock <bpm>|off         start or stop pulses at the clock input, at the
+//!                         PPQN configured on the module
+//! reset                   send a rising edge to the reset input
+//! midi clock <bpm>|off    start or stop MIDI timing clocks at 24 PPQN
+//! midi start|stop|continue
+//!                         send a MIDI transport message
+//! midi position <16ths>   send a MIDI song position
+//! send <line>             send a command over the serial port, e.g.
+//!                         `send div 2 16`, the reply is printed
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::process::exit;
+use std::rc::Rc;
+use std::{env, fs, io};
+
+use cloooock_rs::app::{App, Input, NUM_CHANNELS};
+use cloooock_rs::button::{Button, ButtonEvent};
+use cloooock_rs::display::{Display, GLYPHS, GLYPH_CHARS, SEGMENT_DP};
+use cloooock_rs::eeprom::Eeprom;
+use cloooock_rs::encoder::{AnalogInput, Contact, InterruptSampler, StepsPerDetent};
+use cloooock_rs::midi::{self, Message};
+use cloooock_rs::serial_cmd::{self, Reply};
+use cloooock_rs::shared::Shared;
+use cloooock_rs::state_machine::DeviceState;
+use cloooock_rs::step_queue::StepQueue;
+use cloooock_rs::time::TICK_RATE;
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::OutputPin;
+
+/// Roughly one main loop iteration on the Nano: shifting out a display digit
+/// and updating the channels.
+const DEFAULT_TICKS_PER_LOOP: u32 = 1;
+/// How long a scripted button press is held down, and released afterwards.
+const PRESS_MS: u32 = 20;
+
+const CLK: u8 = 0;
+const DT: u8 = 1;
+
+#[derive(Clone, Copy)]
+enum PinId {
+    Led(usize),
+    Output(usize),
+    DisplayClk,
+    DisplayData,
+    DisplayLatch,
+}
+
+/// Everything wired to the output pins: jacks, LEDs and the two chained shift
+/// registers driving the 7-segment display.
+#[derive(Default)]
+struct Board {
+    now: u64,
+    leds: [bool; NUM_CHANNELS],
+    outputs: [bool; NUM_CHANNELS],
+    display_clk: bool,
+    display_data: bool,
+    display_latch: bool,
+    shift_register: u16,
+    segments: [u8; 4],
+    frame: String,
+}
+
+impl Board {
+    fn drive(&mut self, pin: PinId, level: bool) {
+        match pin {
+            PinId::Led(index) => {
+                if self.leds[index] != level {
+                    self.leds[index] = level;
+                    self.report(&format!("led{} {}", index, on_off(level)));
+                }
+            }
+            PinId::Output(index) => {
+                if self.outputs[index] != level {
+                    self.outputs[index] = level;
+                    self.report(&format!("out{} {}", index, high_low(level)));
+                }
+            }
+            PinId::DisplayClk => {
+                if level && !self.display_clk {
+                    self.shift_register = self.shift_register << 1 | self.display_data as u16;
+                }
+                self.display_clk = level;
+            }
+            PinId::DisplayData => self.display_data = level,
+            PinId::DisplayLatch => {
+                if level && !self.display_latch {
+                    self.latch();
+                }
+                self.display_latch = level;
+            }
+        }
+    }
+
+    /// The digit select byte is shifted out first, followed by the segments.
+    fn latch(&mut self) {
+        let select = (self.shift_register >> 8) as u8;
+        let segments = self.shift_register as u8;
+        for (index, digit) in self.segments.iter_mut().enumerate() {
+            if select & (1 << index) != 0 {
+                *digit = segments;
+            }
+        }
+        // report complete multiplexing scans only
+        if select & 0b1000 != 0 {
+            let frame: String = self.segments.iter().map(|&s| to_text(s)).collect();
+            if frame != self.frame {
+                self.report(&format!("display {}", frame));
+                self.frame = frame;
+            }
+        }
+    }
+
+    fn report(&self, event: &str) {
+        println!("{:>10} {}", self.now, event);
+    }
+}
+
+/// The character a digit shows, followed by `.` when its decimal point is lit.
+fn to_text(segments: u8) -> String {
+    let point = segments & SEGMENT_DP == 0;
+    let character = match GLYPHS
+        .iter()
+        .position(|&glyph| glyph == segments | SEGMENT_DP)
+    {
+        Some(glyph) => GLYPH_CHARS[glyph],
+        None => '?',
+    };
+    match point {
+        true => format!("{}.", character),
+        false => character.to_string(),
+    }
+}
+
+fn on_off(level: bool) -> &'static str {
+    if level {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn high_low(level: bool) -> &'static str {
+    if level {
+        "high"
+    } else {
+        "low"
+    }
+}
+
+#[derive(Clone)]
+struct SimPin {
+    id: PinId,
+    board: Rc<RefCell<Board>>,
+}
+
+impl OutputPin for SimPin {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.board.borrow_mut().drive(self.id, false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.board.borrow_mut().drive(self.id, true);
+        Ok(())
+    }
+}
+
+struct NoDelay;
+
+impl DelayUs<u8> for NoDelay {
+    fn delay_us(&mut self, _us: u8) {}
+}
+
+/// Replaces `TIMER1_COMPA`, the clock input `INT1`, the reset input `PCINT0`
+/// and the encoder sampling `ADC` interrupt, advanced explicitly by the
+/// simulation.
+struct VirtualTimer {
+    shared: Shared,
+    elapsed: u64,
+    /// Tempo of the pulses at the clock input.
+    clock_input: Option<u16>,
+    next_clock_edge: f64,
+    contacts: EncoderContacts,
+    sampler: InterruptSampler,
+    encoder_steps: StepQueue,
+    /// Tempo of the MIDI timing clocks received.
+    midi_clock: Option<u16>,
+    next_midi_clock: f64,
+    /// Timing clocks received since the main loop last read the USART.
+    midi_clocks: u32,
+}
+
+impl VirtualTimer {
+    fn new(contacts: EncoderContacts) -> Self {
+        VirtualTimer {
+            shared: Shared::new(),
+            elapsed: 0,
+            clock_input: None,
+            next_clock_edge: 0.0,
+            contacts,
+            sampler: InterruptSampler::new(StepsPerDetent::Four),
+            encoder_steps: StepQueue::new(),
+            midi_clock: None,
+            next_midi_clock: 0.0,
+            midi_clocks: 0,
+        }
+    }
+
+    /// Everything firing during one tick, the ADC converts at about the
+    /// same rate as the timer.
+    fn interrupt(&mut self) {
+        self.shared.timer_tick();
+        self.elapsed += 1;
+        let channel = match self.sampler.next_contact() {
+            Contact::Clk => CLK,
+            Contact::Dt => DT,
+        };
+        let value = self.contacts.read(&channel);
+        if let Some(step) = self.sampler.conversion(value) {
+            self.encoder_steps.push(step);
+        }
+        if let Some(bpm) = self.clock_input {
+            if self.elapsed as f64 >= self.next_clock_edge {
+                self.shared.clock_edge();
+                let ppqn = self.shared.external_clock.ppqn() as f64;
+                self.next_clock_edge += 60.0 * TICK_RATE as f64 / (bpm as f64 * ppqn);
+            }
+        }
+        if let Some(bpm) = self.midi_clock {
+            if self.elapsed as f64 >= self.next_midi_clock {
+                self.midi_clocks += 1;
+                self.next_midi_clock += 60.0 * TICK_RATE as f64 / (bpm as f64 * midi::PPQN as f64);
+            }
+        }
+    }
+
+    fn set_clock_input(&mut self, bpm: Option<u16>) {
+        self.clock_input = bpm;
+        self.next_clock_edge = self.elapsed as f64;
+    }
+
+    fn set_midi_clock(&mut self, bpm: Option<u16>) {
+        self.midi_clock = bpm;
+        self.next_midi_clock = self.elapsed as f64;
+    }
+}
+
+/// Encoder contacts as seen by the ADC, one `(clk, dt)` sample per pair of
+/// conversions. Both
+/// contacts are pulled up while the encoder rests on a detent.
+#[derive(Clone, Default)]
+struct EncoderContacts {
+    samples: Rc<RefCell<VecDeque<(bool, bool)>>>,
+}
+
+impl EncoderContacts {
+    fn turn(&self, detents: i32) {
+        // Gray code sequence between two detents
+        let clockwise = [(true, false), (false, false), (false, true), (true, true)];
+        let mut samples = self.samples.borrow_mut();
+        for _ in 0..detents.abs() {
+            for &(clk, dt) in &clockwise {
+                samples.push_back(if detents > 0 { (clk, dt) } else { (dt, clk) });
+            }
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.samples.borrow().is_empty()
+    }
+}
+
+impl AnalogInput<u8> for EncoderContacts {
+    fn read(&mut self, channel: &u8) -> u16 {
+        let mut samples = self.samples.borrow_mut();
+        let (clk, dt) = samples.front().copied().unwrap_or((true, true));
+        let level = match *channel {
+            CLK => clk,
+            _ => {
+                // DT is read second, advance to the next sample
+                samples.pop_front();
+                dt
+            }
+        };
+        if level {
+            1023
+        } else {
+            0
+        }
+    }
+}
+
+/// The EEPROM of a fresh module, forgotten when the simulation ends.
+struct RamEeprom([u8; RamEeprom::CAPACITY as usize]);
+
+impl Eeprom for RamEeprom {
+    const CAPACITY: u16 = 1024;
+
+    fn read_byte(&mut self, address: u16) -> u8 {
+        self.0[address as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.0[address as usize] = value;
+    }
+}
+
+/// What the firmware writes to the serial port.
+#[derive(Default)]
+struct SerialText(String);
+
+impl ufmt::uWrite for SerialText {
+    type Error = std::convert::Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        self.0.push_str(s);
+        Ok(())
+    }
+}
+
+/// A push button and the debouncing the firmware runs on it.
+#[derive(Default)]
+struct PanelButton {
+    pressed: bool,
+    button: Button,
+}
+
+impl PanelButton {
+    fn update(&mut self, now: u32) -> Option<ButtonEvent> {
+        self.button.update(self.pressed, now)
+    }
+}
+
+enum Command {
+    Wait(u64),
+    Turn(i32),
+    Press(usize),
+    Hold(usize, bool),
+    Clock(Option<u16>),
+    Reset,
+    MidiClock(Option<u16>),
+    Midi(Message),
+    Send(String),
+}
+
+const PAUSE_BUTTON: usize = 0;
+const ENCODER_BUTTON: usize = 1;
+
+fn parse_ticks(arg: &str) -> Option<u64> {
+    match arg.strip_suffix("ms") {
+        Some(ms) => ms
+            .parse::<u64>()
+            .ok()
+            .map(|ms| ms * TICK_RATE as u64 / 1000),
+        None => arg.parse().ok(),
+    }
+}
+
+fn parse(script: &str) -> Result<Vec<Command>, String> {
+    let mut commands = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            ["wait", ticks] => parse_ticks(ticks).map(Command::Wait),
+            ["turn", detents] => detents.parse().ok().map(Command::Turn),
+            ["press", "pause"] => Some(Command::Press(PAUSE_BUTTON)),
+            ["press", "encoder"] => Some(Command::Press(ENCODER_BUTTON)),
+            ["hold", "pause"] => Some(Command::Hold(PAUSE_BUTTON, true)),
+            ["hold", "encoder"] => Some(Command::Hold(ENCODER_BUTTON, true)),
+            ["release", "pause"] => Some(Command::Hold(PAUSE_BUTTON, false)),
+            ["release", "encoder"] => Some(Command::Hold(ENCODER_BUTTON, false)),
+            ["clock", "off"] => Some(Command::Clock(None)),
+            ["reset"] => Some(Command::Reset),
+            ["midi", "clock", "off"] => Some(Command::MidiClock(None)),
+            ["midi", "clock", bpm] => bpm
+                .parse()
+                .ok()
+                .filter(|&bpm| bpm > 0)
+                .map(|bpm| Command::MidiClock(Some(bpm))),
+            ["midi", "start"] => Some(Command::Midi(Message::Start)),
+            ["midi", "stop"] => Some(Command::Midi(Message::Stop)),
+            ["midi", "continue"] => Some(Command::Midi(Message::Continue)),
+            ["midi", "position", position] => position
+                .parse()
+                .ok()
+                .map(|position| Command::Midi(Message::SongPosition(position))),
+            ["send", ..] => Some(Command::Send(line["send".len()..].trim().to_string())),
+            ["clock", bpm] => bpm
+                .parse()
+                .ok()
+                .filter(|&bpm| bpm > 0)
+                .map(|bpm| Command::Clock(Some(bpm))),
+            _ => None,
+        };
+        match command {
+            Some(command) => commands.push(command),
+            None => return Err(format!("line {}: cannot parse `{}`", number + 1, line)),
+        }
+    }
+    Ok(commands)
+}
+
+struct Simulator {
+    app: App<SimPin, NoDelay, RamEeprom>,
+    contacts: EncoderContacts,
+    buttons: [PanelButton; 2],
+    timer: VirtualTimer,
+    board: Rc<RefCell<Board>>,
+    ticks_per_loop: u32,
+    state: DeviceState,
+}
+
+impl Simulator {
+    fn new(ticks_per_loop: u32) -> Self {
+        let board = Rc::new(RefCell::new(Board::default()));
+        let pin = |id| SimPin {
+            id,
+            board: board.clone(),
+        };
+        let display = Display::new(
+            pin(PinId::DisplayClk),
+            pin(PinId::DisplayData),
+            pin(PinId::DisplayLatch),
+            NoDelay,
+        );
+        let channel_pins =
+            [0, 1, 2, 3].map(|index| (pin(PinId::Led(index)), pin(PinId::Output(index))));
+        let contacts = EncoderContacts::default();
+        let app = App::new(RamEeprom([0xFF; 1024]), channel_pins, display);
+        Simulator {
+            state: app.state(),
+            app,
+            timer: VirtualTimer::new(contacts.clone()),
+            contacts,
+            buttons: Default::default(),
+            board,
+            ticks_per_loop,
+        }
+    }
+
+    /// One main loop iteration, preceded by the timer interrupts that fire
+    /// while it runs.
+    fn run_loop(&mut self) {
+        for _ in 0..self.ticks_per_loop {
+            self.timer.interrupt();
+        }
+        self.board.borrow_mut().now = self.timer.elapsed;
+        let now = self.timer.shared.timestamp;
+        for _ in 0..std::mem::take(&mut self.timer.midi_clocks) {
+            self.app
+                .receive_midi(Message::TimingClock, &mut self.timer.shared);
+        }
+        let input = Input {
+            pause_button: self.buttons[PAUSE_BUTTON].update(now),
+            encoder_button: self.buttons[ENCODER_BUTTON].update(now),
+            pause_held: self.buttons[PAUSE_BUTTON].button.is_pressed(),
+            encoder_held: self.buttons[ENCODER_BUTTON].button.is_pressed(),
+            encoder: self.timer.encoder_steps.drain(),
+        };
+        self.app.step(input, &mut self.timer.shared);
+        while let Some(byte) = self.app.midi_byte() {
+            let message = match byte {
+                midi::START => "start",
+                midi::STOP => "stop",
+                midi::CONTINUE => "continue",
+                _ => continue,
+            };
+            self.board.borrow().report(&format!("midi {}", message));
+        }
+        if self.app.state() != self.state {
+            self.state = self.app.state();
+            self.board
+                .borrow()
+                .report(&format!("state {:?}", self.state));
+        }
+    }
+
+    fn run_for(&mut self, ticks: u64) {
+        let until = self.timer.elapsed + ticks;
+        while self.timer.elapsed < until {
+            self.run_loop();
+        }
+    }
+
+    fn execute(&mut self, command: &Command) {
+        match command {
+            Command::Wait(ticks) => self.run_for(*ticks),
+            Command::Clock(bpm) => self.timer.set_clock_input(*bpm),
+            Command::Reset => self.timer.shared.reset_edge(),
+            Command::MidiClock(bpm) => self.timer.set_midi_clock(*bpm),
+            Command::Midi(message) => {
+                self.app.receive_midi(*message, &mut self.timer.shared);
+                self.run_loop();
+            }
+            Command::Hold(button, pressed) => {
+                self.buttons[*button].pressed = *pressed;
+                self.run_loop();
+            }
+            Command::Turn(detents) => {
+                self.contacts.turn(*detents);
+                while !self.contacts.is_idle() {
+                    self.run_loop();
+                }
+            }
+            Command::Press(button) => {
+                let button = *button;
+                let press_ticks = (PRESS_MS * TICK_RATE / 1000) as u64;
+                self.buttons[button].pressed = true;
+                self.run_for(press_ticks);
+                self.buttons[button].pressed = false;
+                self.run_for(press_ticks);
+            }
+            Command::Send(line) => {
+                let reply = match serial_cmd::parse(line) {
+                    Ok(command) => self.app.execute(command, &mut self.timer.shared),
+                    Err(error) => Reply::Error(error),
+                };
+                let mut text = SerialText::default();
+                reply.write(&mut text).unwrap();
+                self.board
+                    .borrow()
+                    .report(&format!("serial {}", text.0.trim_end()));
+                self.run_loop();
+            }
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: cloooock-sim [--ticks-per-loop N] [SCRIPT]");
+    exit(2)
+}
+
+fn main() {
+    let mut ticks_per_loop = DEFAULT_TICKS_PER_LOOP;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ticks-per-loop" => {
+                ticks_per_loop = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => n,
+                    _ => usage(),
+                }
+            }
+            "-h" | "--help" => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let script = match &path {
+        Some(path) => fs::read_to_string(path),
+        None => {
+            let mut script = String::new();
+            io::stdin().read_to_string(&mut script).map(|_| script)
+        }
+    };
+    let script = script.unwrap_or_else(|error| {
+        eprintln!("cloooock-sim: {}", error);
+        exit(1)
+    });
+    let commands = parse(&script).unwrap_or_else(|error| {
+        eprintln!("cloooock-sim: {}", error);
+        exit(1)
+    });
+
+    let mut simulator = Simulator::new(ticks_per_loop);
+    for command in &commands {
+        simulator.execute(command);
+    }
+}
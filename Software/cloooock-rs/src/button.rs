@@ -0,0 +1,246 @@
+use crate::time::TICK_RATE;
+
+const fn ms(ms: u32) -> u32 {
+    ms * TICK_RATE / 1000
+}
+
+/// Gesture timings in ticks of [crate::shared::Shared::timestamp].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ButtonTimings {
+    /// The contact has to settle this long before a change counts.
+    pub debounce: u32,
+    /// Held this long the press becomes a [ButtonEvent::LongPress].
+    pub long_press: u32,
+    /// A press this soon after a click is a [ButtonEvent::DoubleClick].
+    pub double_click: u32,
+    /// Interval of [ButtonEvent::Hold] after the long press.
+    pub hold_repeat: u32,
+}
+
+impl ButtonTimings {
+    pub const DEFAULT: ButtonTimings = ButtonTimings {
+        debounce: ms(10),
+        long_press: ms(800),
+        double_click: ms(300),
+        hold_repeat: ms(200),
+    };
+}
+
+impl Default for ButtonTimings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ButtonEvent {
+    /// The button went down.
+    Press,
+    /// The button came up after a short press, i.e. a click. Releases ending
+    /// a long press or a double click are swallowed by those gestures.
+    Release,
+    /// Held down for [ButtonTimings::long_press].
+    LongPress,
+    /// Pressed again shortly after a click, replaces that [ButtonEvent::Press].
+    DoubleClick,
+    /// Still held after the long press, counting the repeats from 1.
+    Hold(u8),
+}
+
+/// Debounced push button turning raw levels into [ButtonEvent]s.
+pub struct Button {
+    timings: ButtonTimings,
+    /// Debounced level.
+    pressed: bool,
+    /// Raw level and when it was first seen.
+    raw: bool,
+    raw_since: u32,
+    pressed_at: u32,
+    /// The current press already became a long press or double click.
+    gesture: bool,
+    holds: u8,
+    last_click: Option<u32>,
+}
+
+impl Button {
+    pub const fn new(timings: ButtonTimings) -> Self {
+        Button {
+            timings,
+            pressed: false,
+            raw: false,
+            raw_since: 0,
+            pressed_at: 0,
+            gesture: false,
+            holds: 0,
+            last_click: None,
+        }
+    }
+
+    /// Debounced state of the button.
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    /// Feeds the raw level read at `now`, call on every main loop iteration.
+    pub fn update(&mut self, pressed: bool, now: u32) -> Option<ButtonEvent> {
+        if pressed != self.raw {
+            self.raw = pressed;
+            self.raw_since = now;
+        }
+        let settled = now.wrapping_sub(self.raw_since) >= self.timings.debounce;
+        if settled && self.raw != self.pressed {
+            self.pressed = self.raw;
+            return if self.pressed {
+                Some(self.press(now))
+            } else {
+                self.release(now)
+            };
+        }
+        if self.pressed {
+            self.held(now)
+        } else {
+            None
+        }
+    }
+
+    fn press(&mut self, now: u32) -> ButtonEvent {
+        self.pressed_at = now;
+        self.holds = 0;
+        let double_click = matches!(
+            self.last_click.take(),
+            Some(click) if now.wrapping_sub(click) <= self.timings.double_click
+        );
+        self.gesture = double_click;
+        if double_click {
+            ButtonEvent::DoubleClick
+        } else {
+            ButtonEvent::Press
+        }
+    }
+
+    fn release(&mut self, now: u32) -> Option<ButtonEvent> {
+        if self.gesture {
+            return None;
+        }
+        self.last_click = Some(now);
+        Some(ButtonEvent::Release)
+    }
+
+    fn held(&mut self, now: u32) -> Option<ButtonEvent> {
+        let held_for = now.wrapping_sub(self.pressed_at);
+        if held_for < self.timings.long_press {
+            return None;
+        }
+        if !self.gesture {
+            self.gesture = true;
+            return Some(ButtonEvent::LongPress);
+        }
+        if self.holds == u8::MAX || self.timings.hold_repeat == 0 {
+            return None;
+        }
+        let repeats = (held_for - self.timings.long_press) / self.timings.hold_repeat;
+        if repeats > self.holds as u32 {
+            self.holds += 1;
+            return Some(ButtonEvent::Hold(self.holds));
+        }
+        None
+    }
+}
+
+impl Default for Button {
+    fn default() -> Self {
+        Self::new(ButtonTimings::DEFAULT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Runs `levels`, a list of `(pressed, ticks)` spans, one update per tick.
+    fn events(levels: &[(bool, u32)]) -> Vec<(u32, ButtonEvent)> {
+        let mut button = Button::default();
+        let mut now = 0;
+        let mut events = Vec::new();
+        for &(pressed, ticks) in levels {
+            for _ in 0..ticks {
+                if let Some(event) = button.update(pressed, now) {
+                    events.push((now, event));
+                }
+                now += 1;
+            }
+        }
+        events
+    }
+
+    fn kinds(levels: &[(bool, u32)]) -> Vec<ButtonEvent> {
+        events(levels).into_iter().map(|(_, event)| event).collect()
+    }
+
+    #[test]
+    fn click_is_debounced() {
+        let bouncy = [
+            (true, 20),
+            (false, 30),
+            (true, 40),
+            (false, 10),
+            (true, 1000),
+            (false, 5000),
+        ];
+        assert_eq!(
+            events(&bouncy),
+            [(200, ButtonEvent::Press), (1200, ButtonEvent::Release)]
+        );
+    }
+
+    #[test]
+    fn long_press_then_hold() {
+        assert_eq!(
+            kinds(&[(true, ms(1250)), (false, ms(100))]),
+            [
+                ButtonEvent::Press,
+                ButtonEvent::LongPress,
+                ButtonEvent::Hold(1),
+                ButtonEvent::Hold(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn double_click() {
+        let clicks = [
+            (true, ms(50)),
+            (false, ms(100)),
+            (true, ms(50)),
+            (false, ms(100)),
+        ];
+        assert_eq!(
+            kinds(&clicks),
+            [
+                ButtonEvent::Press,
+                ButtonEvent::Release,
+                ButtonEvent::DoubleClick
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_clicks_are_single() {
+        let clicks = [
+            (true, ms(50)),
+            (false, ms(500)),
+            (true, ms(50)),
+            (false, ms(100)),
+        ];
+        assert_eq!(
+            kinds(&clicks),
+            [
+                ButtonEvent::Press,
+                ButtonEvent::Release,
+                ButtonEvent::Press,
+                ButtonEvent::Release
+            ]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicU8 = AtomicU8::new(0);
+
+/// Single producer, single consumer ring buffer of `N - 1` bytes between an
+/// interrupt and the main loop, either way round. Only atomic loads and
+/// stores are used, the AVR has no compare and swap.
+pub struct ByteQueue<const N: usize> {
+    bytes: [AtomicU8; N],
+    /// Next slot to pop, only written by the consumer.
+    head: AtomicU8,
+    /// Next slot to push, only written by the producer.
+    tail: AtomicU8,
+}
+
+/// [ByteQueue] had no room left.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Full;
+
+impl<const N: usize> ByteQueue<N> {
+    pub const fn new() -> Self {
+        ByteQueue {
+            bytes: [EMPTY; N],
+            head: AtomicU8::new(0),
+            tail: AtomicU8::new(0),
+        }
+    }
+
+    fn next(index: u8) -> u8 {
+        (index + 1) % N as u8
+    }
+
+    /// Drops the byte if the queue is full.
+    pub fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = Self::next(tail);
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        self.bytes[tail as usize].store(byte, Ordering::Relaxed);
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        let byte = self.peek()?;
+        let head = self.head.load(Ordering::Relaxed);
+        self.head.store(Self::next(head), Ordering::Release);
+        Some(byte)
+    }
+
+    /// The byte [Self::pop] returns next, the queue stays non-empty for the
+    /// producer until it is popped.
+    pub fn peek(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(self.bytes[head as usize].load(Ordering::Relaxed))
+    }
+
+    pub fn is_full(&self) -> bool {
+        Self::next(self.tail.load(Ordering::Acquire)) == self.head.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+impl<const N: usize> Default for ByteQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Queues text for the interrupt to send, e.g. a
+/// [crate::serial_cmd::Reply].
+impl<const N: usize> ufmt::uWrite for &ByteQueue<N> {
+    type Error = Full;
+
+    fn write_str(&mut self, text: &str) -> Result<(), Full> {
+        for byte in text.bytes() {
+            if !self.push(byte) {
+                return Err(Full);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ufmt::uWrite;
+
+    #[test]
+    fn keeps_one_slot_free() {
+        let queue = ByteQueue::<4>::new();
+        assert!(queue.is_empty());
+        assert!(queue.push(1) && queue.push(2) && queue.push(3));
+        assert!(queue.is_full() && !queue.push(4));
+        assert_eq!(queue.peek(), Some(1));
+        assert_eq!(queue.pop(), Some(1));
+        assert!(queue.push(4));
+        let popped: std::vec::Vec<_> = core::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(popped, [2, 3, 4]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn writes_text() {
+        let queue = ByteQueue::<8>::new();
+        assert_eq!((&queue).write_str("ok\r\n"), Ok(()));
+        assert_eq!((&queue).write_str("error"), Err(Full));
+        let popped: std::vec::Vec<_> = core::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(popped, b"ok\r\nerr");
+    }
+}
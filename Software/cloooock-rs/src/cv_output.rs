@@ -1,13 +1,44 @@
-use arduino_hal::port::{mode::Output, Pin};
+use embedded_hal::digital::v2::OutputPin;
 
-//use crate::time::{TicksPerBar, BPM, TICK_RATE};
+use crate::display::{
+    Displayable, GLYPH_A, GLYPH_BLANK, GLYPH_C, GLYPH_DASH, GLYPH_E, GLYPH_L, GLYPH_P, GLYPH_R,
+    GLYPH_S, GLYPH_SMALL_C, GLYPH_SMALL_O, GLYPH_SMALL_U, GLYPH_T,
+};
+use crate::euclid::Euclid;
+use crate::rng::Rng;
+use crate::time::TICK_RATE;
 
+/// Largest numerator and denominator selectable from the front panel.
+pub const MAX_RATIO: u16 = 128;
+
+/// Steps `value` by the sign of `change`, wrapping around within `1..=MAX_RATIO`.
+fn step_ratio(value: u16, change: i8) -> u16 {
+    if change < 0 {
+        if value > 1 {
+            value - 1
+        } else {
+            MAX_RATIO
+        }
+    } else if change > 0 {
+        if value < MAX_RATIO {
+            value + 1
+        } else {
+            1
+        }
+    } else {
+        value
+    }
+}
+
+/// Fraction of a bar between two edges of a channel: `numerator / denominator`.
+/// A denominator above the numerator divides the bar, a numerator above the
+/// denominator stretches one edge over several bars.
 pub struct Prescaler {
     numerator: u16,
     denominator: u16,
 }
 impl Prescaler {
-    pub fn new(numerator: u16, denominator: u16) -> Self {
+    pub const fn new(numerator: u16, denominator: u16) -> Self {
         Prescaler {
             numerator,
             denominator,
@@ -22,14 +53,209 @@ impl Prescaler {
     }
 }
 
-pub struct ClockOutput {
+/// Straight, no swing.
+pub const MIN_SWING_PERCENT: u8 = 50;
+/// The second pulse of a pair halfway to the next pair.
+pub const MAX_SWING_PERCENT: u8 = 75;
+
+/// Every pulse fires.
+pub const MAX_PROBABILITY_PERCENT: u8 = 100;
+
+/// Latest start of the pulses, in percent of their period.
+pub const MAX_OFFSET_PERCENT: u8 = 99;
+
+pub const MAX_TRIGGER_MS: u8 = 50;
+pub const MAX_DUTY_PERCENT: u8 = 99;
+
+/// Shape of the pulse a channel outputs on every other edge of its division.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum GateMode {
+    /// 50% square wave, toggling on every edge.
+    #[default]
+    Toggle,
+    /// Fixed length trigger of `1..=MAX_TRIGGER_MS` milliseconds.
+    Trigger(u8),
+    /// Gate for `1..=MAX_DUTY_PERCENT` percent of the pulse period.
+    Duty(u8),
+}
+
+impl GateMode {
+    const COUNT: i16 = 1 + MAX_TRIGGER_MS as i16 + MAX_DUTY_PERCENT as i16;
+
+    /// Position in the front panel list: toggle, the trigger lengths, then the
+    /// duty cycles.
+    fn index(self) -> i16 {
+        match self {
+            GateMode::Toggle => 0,
+            GateMode::Trigger(ms) => ms as i16,
+            GateMode::Duty(percent) => MAX_TRIGGER_MS as i16 + percent as i16,
+        }
+    }
+
+    fn from_index(index: i16) -> Self {
+        match index {
+            0 => GateMode::Toggle,
+            i if i <= MAX_TRIGGER_MS as i16 => GateMode::Trigger(i as u8),
+            i => GateMode::Duty((i - MAX_TRIGGER_MS as i16) as u8),
+        }
+    }
+
+    /// Moves `change` steps through the front panel list, wrapping around.
+    pub fn step(self, change: i8) -> Self {
+        Self::from_index((self.index() + change as i16).rem_euclid(Self::COUNT))
+    }
+
+    /// Length of the gate within a pulse `period`, `None` when toggling.
+    fn gate_ticks(self, period: u32) -> Option<u32> {
+        let ticks = match self {
+            GateMode::Toggle => return None,
+            GateMode::Trigger(ms) => ms as u32 * TICK_RATE / 1000,
+            GateMode::Duty(percent) => period * percent as u32 / 100,
+        };
+        // always leave a low between two pulses
+        Some(ticks.clamp(1, period.saturating_sub(1).max(1)))
+    }
+}
+
+impl Displayable for GateMode {
+    /// `-50-` when toggling, `t010` for a 10 ms trigger and `P025` for a 25%
+    /// duty cycle.
+    fn display_digit(&self, index: u8) -> u8 {
+        match (*self, index) {
+            (GateMode::Toggle, 0 | 3) => GLYPH_DASH,
+            (GateMode::Toggle, _) => 50u16.display_digit(index + 1),
+            (GateMode::Trigger(_), 0) => GLYPH_T,
+            (GateMode::Duty(_), 0) => GLYPH_P,
+            (GateMode::Trigger(value) | GateMode::Duty(value), _) => {
+                (value as u16).display_digit(index)
+            }
+        }
+    }
+}
+
+pub const MIN_RATCHET_COUNT: u8 = 2;
+pub const MAX_RATCHET_COUNT: u8 = 8;
+/// Longest gap between two bursts, in pulses.
+pub const MAX_RATCHET_EVERY: u8 = 8;
+
+/// Burst played in [ChannelMode::Ratchet]: `count` short pulses in place of
+/// every `every`th pulse of the division.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ratchet {
+    count: u8,
+    every: u8,
+}
+
+/// Three pulses in place of every pulse.
+pub const DEFAULT_RATCHET: Ratchet = Ratchet { count: 3, every: 1 };
+
+impl Default for Ratchet {
+    fn default() -> Self {
+        DEFAULT_RATCHET
+    }
+}
+
+impl Ratchet {
+    /// `None` unless `count` is within `MIN_RATCHET_COUNT..=MAX_RATCHET_COUNT`
+    /// and `every` within `1..=MAX_RATCHET_EVERY`.
+    pub fn try_new(count: u8, every: u8) -> Option<Self> {
+        let valid = (MIN_RATCHET_COUNT..=MAX_RATCHET_COUNT).contains(&count)
+            && (1..=MAX_RATCHET_EVERY).contains(&every);
+        valid.then_some(Ratchet { count, every })
+    }
+
+    /// Clamps both into range.
+    pub fn new(count: u8, every: u8) -> Self {
+        Ratchet {
+            count: count.clamp(MIN_RATCHET_COUNT, MAX_RATCHET_COUNT),
+            every: every.clamp(1, MAX_RATCHET_EVERY),
+        }
+    }
+
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    pub fn every(&self) -> u8 {
+        self.every
+    }
+
+    /// Steps the pulses per burst, wrapping around.
+    pub fn step_count(self, change: i8) -> Self {
+        let range = (MAX_RATCHET_COUNT - MIN_RATCHET_COUNT + 1) as i8;
+        let count = (self.count - MIN_RATCHET_COUNT) as i8 + change;
+        Ratchet::new(
+            count.rem_euclid(range) as u8 + MIN_RATCHET_COUNT,
+            self.every,
+        )
+    }
+
+    /// Steps the pulses between bursts, wrapping around.
+    pub fn step_every(self, change: i8) -> Self {
+        let every = (self.every - 1) as i8 + change;
+        Ratchet::new(
+            self.count,
+            every.rem_euclid(MAX_RATCHET_EVERY as i8) as u8 + 1,
+        )
+    }
+}
+
+/// Length of the trigger sent by a channel in [ChannelMode::ResetOut].
+pub const RESET_TRIGGER_MS: u8 = 10;
+
+/// What a channel outputs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ChannelMode {
+    /// Divided or multiplied clock following the [Prescaler].
+    #[default]
+    Clock,
+    /// A trigger at the start of every bar, keeping downstream sequencers
+    /// aligned.
+    ResetOut,
+    /// A [Euclid] pattern stepping on the pulses of the [Prescaler], only
+    /// its hits reach the output.
+    Euclid,
+    /// Pulses of the [Prescaler] replaced by a [Ratchet] burst, for rolls.
+    Ratchet,
+}
+
+impl ChannelMode {
+    pub fn step(self, change: i8) -> Self {
+        if change == 0 {
+            return self;
+        }
+        let modes = [
+            ChannelMode::Clock,
+            ChannelMode::ResetOut,
+            ChannelMode::Euclid,
+            ChannelMode::Ratchet,
+        ];
+        let index = modes.iter().position(|&mode| mode == self).unwrap_or(0) as i8;
+        modes[(index + change.signum()).rem_euclid(modes.len() as i8) as usize]
+    }
+}
+
+impl Displayable for ChannelMode {
+    /// `CLoc`, `rSt`, `Eucl` or `rAt`.
+    fn display_digit(&self, index: u8) -> u8 {
+        let glyphs = match self {
+            ChannelMode::Clock => [GLYPH_C, GLYPH_L, GLYPH_SMALL_O, GLYPH_SMALL_C],
+            ChannelMode::ResetOut => [GLYPH_R, GLYPH_S, GLYPH_T, GLYPH_BLANK],
+            ChannelMode::Euclid => [GLYPH_E, GLYPH_SMALL_U, GLYPH_SMALL_C, GLYPH_L],
+            ChannelMode::Ratchet => [GLYPH_R, GLYPH_A, GLYPH_T, GLYPH_BLANK],
+        };
+        glyphs[index as usize]
+    }
+}
+
+pub struct ClockOutput<P: OutputPin> {
     state: bool,
-    led_pin: Pin<Output>,
-    output_pin: Pin<Output>,
+    led_pin: P,
+    output_pin: P,
 }
 
-impl ClockOutput {
-    pub fn new(led_pin: Pin<Output>, output_pin: Pin<Output>) -> Self {
+impl<P: OutputPin> ClockOutput<P> {
+    pub fn new(led_pin: P, output_pin: P) -> Self {
         ClockOutput {
             state: false,
             led_pin,
@@ -39,14 +265,14 @@ impl ClockOutput {
 
     pub fn set_high(&mut self) {
         self.state = true;
-        self.led_pin.set_high();
-        self.output_pin.set_high();
+        self.led_pin.set_high().ok();
+        self.output_pin.set_high().ok();
     }
 
     pub fn set_low(&mut self) {
         self.state = false;
-        self.led_pin.set_low();
-        self.output_pin.set_low();
+        self.led_pin.set_low().ok();
+        self.output_pin.set_low().ok();
     }
 
     pub fn toggle(&mut self) {
@@ -58,52 +284,308 @@ impl ClockOutput {
     }
 }
 
-// reset at the start of every bar.
-pub struct ClockChannel {
+// reset at the start of every cycle of `numerator` bars, after which the
+// ratio lines up with the bar again.
+pub struct ClockChannel<P: OutputPin> {
+    mode: ChannelMode,
     prescaler: Prescaler,
+    /// Next edge, in ticks since the start of the cycle.
     threshold: u32,
     threshold_interval: u32,
-    output: ClockOutput,
+    /// Ticks left over when dividing the bar, in 1/denominator ticks.
+    interval_remainder: u32,
+    /// Fraction of a tick accumulated from [Self::interval_remainder], in
+    /// 1/denominator ticks.
+    error: u32,
+    output: ClockOutput<P>,
+    gate_mode: GateMode,
+    /// Level of the 50% square wave the division describes, the output follows
+    /// it directly when toggling and starts a gate on its rising edges otherwise.
+    phase: bool,
+    /// End of the current gate, in ticks since the start of the cycle.
+    gate_end: Option<u32>,
+    /// Percent of a pair of pulses before the second one starts, see
+    /// [Self::swing_delay].
+    swing: u8,
+    /// Position of the next threshold within a pair of pulses: the first
+    /// pulse falls at 1, the second rises at 2 and falls at 3.
+    toggle: u8,
+    euclid: Euclid,
+    /// Step of [Self::euclid] the next pulse plays, kept across bars so
+    /// patterns longer or shorter than the bar drift against it.
+    step: u8,
+    /// Percent chance of every pulse to fire, see [Self::fires].
+    probability: u8,
+    rng: Rng,
+    /// Delay of the pulses after the start of the cycle, in percent of their
+    /// period.
+    offset: u8,
+    ratchet: Ratchet,
+    /// Pulses since the last burst, a burst is due at zero.
+    beat: u8,
+    /// Position the current burst started at.
+    burst_start: u32,
+    /// Ticks the current burst is spread over.
+    burst_span: u32,
+    /// Output changes left in the current burst.
+    burst_edges: u8,
+    bar_ticks: u32,
+    /// Bars completed in the current cycle.
+    bar: u16,
     previous_ticks: u32,
 }
 
-impl ClockChannel {
-    pub fn new(
-        led_pin: Pin<Output>,
-        output_pin: Pin<Output>,
-        prescaler: Prescaler,
-        ticks_per_bar: u32,
-    ) -> Self {
-        ClockChannel {
-            threshold_interval: ticks_per_bar / prescaler.denominator as u32
-                * prescaler.numerator as u32,
-            threshold: ticks_per_bar / prescaler.denominator as u32 * prescaler.numerator as u32,
+impl<P: OutputPin> ClockChannel<P> {
+    pub fn new(led_pin: P, output_pin: P, prescaler: Prescaler, ticks_per_bar: u32) -> Self {
+        let mut channel = ClockChannel {
+            mode: ChannelMode::default(),
+            threshold_interval: 0,
+            interval_remainder: 0,
+            error: 0,
+            threshold: 0,
             prescaler,
             output: ClockOutput::new(led_pin, output_pin),
+            gate_mode: GateMode::default(),
+            phase: false,
+            gate_end: None,
+            swing: MIN_SWING_PERCENT,
+            toggle: 1,
+            euclid: Euclid::default(),
+            step: 0,
+            probability: MAX_PROBABILITY_PERCENT,
+            rng: Rng::new(0),
+            offset: 0,
+            ratchet: Ratchet::default(),
+            beat: 0,
+            burst_start: 0,
+            burst_span: 0,
+            burst_edges: 0,
+            bar_ticks: ticks_per_bar,
+            bar: 0,
             previous_ticks: 0,
-        }
+        };
+        channel.calculate_threshold(ticks_per_bar);
+        channel.advance_threshold();
+        channel
     }
 
     pub fn calculate_threshold(&mut self, bar_ticks: u32) {
-        self.threshold_interval =
-            bar_ticks / self.prescaler.denominator as u32 * self.prescaler.numerator as u32;
+        let denominator = self.prescaler.denominator as u32;
+        let ticks = bar_ticks * self.prescaler.numerator as u32;
+        self.bar_ticks = bar_ticks;
+        self.threshold_interval = ticks / denominator;
+        self.interval_remainder = ticks % denominator;
+        self.error %= denominator;
 
         // if removed stops rapid pulses but it takes time for all channels to catch up and sync
         // self.reset_threshold();
     }
+    /// Restarts the cycle and the [Euclid] pattern.
     pub fn reset_threshold(&mut self) {
-        self.threshold = self.threshold_interval;
+        self.step = 0;
+        self.beat = 0;
+        self.restart_cycle();
+    }
+
+    fn restart_cycle(&mut self) {
+        self.threshold = 0;
+        self.error = 0;
+        self.bar = 0;
         self.previous_ticks = 0;
-        self.output.set_high();
+        let offset = self.offset_ticks();
+        if offset == 0 {
+            self.burst_edges = 0;
+            self.advance_threshold();
+            self.phase = true;
+            self.toggle = 1;
+            self.start_phase(0, 2 * self.threshold_interval);
+        } else {
+            // the last pulse of the previous cycle plays out, the first one
+            // of this cycle comes `offset` ticks in
+            let cycle_ticks = self.prescaler.numerator as u32 * self.bar_ticks;
+            self.gate_end = self.gate_end.map(|end| end.saturating_sub(cycle_ticks));
+            self.burst_start = self.burst_start.wrapping_sub(cycle_ticks);
+            self.threshold = offset;
+            if offset > self.threshold_interval {
+                // the fall of the last pulse is still ahead
+                self.phase = true;
+                self.toggle = 3;
+                self.retreat_threshold();
+            } else {
+                self.phase = false;
+                self.toggle = 0;
+                if self.gate_mode == GateMode::Toggle {
+                    self.output.set_low();
+                }
+            }
+        }
         //self.output.toggle();
     }
 
+    /// Ticks from the start of the cycle to the first pulse. Bar resets are
+    /// never delayed.
+    fn offset_ticks(&self) -> u32 {
+        match self.mode {
+            ChannelMode::ResetOut => 0,
+            _ => self.interval_percent(2 * self.offset as u32),
+        }
+    }
+
+    /// `percent` of the threshold interval, up to a few intervals.
+    fn interval_percent(&self, percent: u32) -> u32 {
+        // in u64, cycles of many bars at slow tempos overflow a u32
+        (self.threshold_interval as u64 * percent as u64 / 100) as u32
+    }
+
+    /// Called at the start of every bar, restarts the channel once its cycle of
+    /// `numerator` bars is complete.
+    pub fn next_bar(&mut self) {
+        self.bar += 1;
+        if self.bar >= self.prescaler.numerator || self.mode == ChannelMode::ResetOut {
+            self.restart_cycle();
+        }
+        self.previous_ticks = 0;
+    }
+
+    /// Moves the threshold one interval ahead. The remainder of the division is
+    /// carried Bresenham style, so threshold `k` lands on
+    /// `k * bar_ticks * numerator / denominator` and the intervals add up to
+    /// the cycle exactly.
+    fn advance_threshold(&mut self) {
+        self.threshold += self.threshold_interval;
+        self.error += self.interval_remainder;
+        if self.error >= self.prescaler.denominator as u32 {
+            self.error -= self.prescaler.denominator as u32;
+            self.threshold += 1;
+        }
+    }
+
+    /// Moves the threshold one interval back, undoing [Self::advance_threshold]
+    /// so the carry continues from the threshold after.
+    fn retreat_threshold(&mut self) {
+        self.threshold -= self.threshold_interval;
+        if self.error < self.interval_remainder {
+            self.error += self.prescaler.denominator as u32;
+            self.threshold -= 1;
+        }
+        self.error -= self.interval_remainder;
+    }
+
+    /// How late the edges of the second pulse of every pair come. At
+    /// [MAX_SWING_PERCENT] it rises where it would fall when straight, and
+    /// falls a tick before the next pair.
+    fn swing_delay(&self) -> u32 {
+        let delay = self.interval_percent(4 * (self.swing - MIN_SWING_PERCENT) as u32);
+        match self.toggle {
+            2 => delay,
+            3 => delay.min(self.threshold_interval.saturating_sub(1)),
+            _ => 0,
+        }
+    }
+
+    /// Whether the pulse starting now is a hit of the pattern, moves on to
+    /// the next step.
+    fn next_step(&mut self) -> bool {
+        let hit = self.euclid.is_hit(self.step);
+        self.step = (self.step + 1) % self.euclid.steps();
+        hit
+    }
+
+    /// Whether the pulse starting now is played: a hit of the pattern in
+    /// [ChannelMode::Euclid], drawn with [Self::probability] in the other
+    /// modes. Bar resets always fire.
+    fn fires(&mut self) -> bool {
+        match self.mode {
+            ChannelMode::ResetOut => true,
+            ChannelMode::Clock | ChannelMode::Ratchet => self.rng.chance(self.probability),
+            ChannelMode::Euclid => self.next_step() && self.rng.chance(self.probability),
+        }
+    }
+
+    /// Drives the output for the phase starting at `position`, `period` ticks
+    /// before the next pulse.
+    fn start_phase(&mut self, position: u32, period: u32) {
+        if self.phase && !self.fires() {
+            // a rest, stay low until the next pulse
+            self.output.set_low();
+            return;
+        }
+        if self.mode == ChannelMode::Ratchet {
+            if self.phase && self.next_beat() {
+                self.start_burst(position, period / 2);
+                return;
+            }
+            if self.burst_edges > 0 {
+                // the burst ends on its own
+                return;
+            }
+        }
+        let gate_ticks = match self.mode {
+            ChannelMode::Clock | ChannelMode::Euclid | ChannelMode::Ratchet => {
+                self.gate_mode.gate_ticks(period)
+            }
+            ChannelMode::ResetOut => Some(RESET_TRIGGER_MS as u32 * TICK_RATE / 1000),
+        };
+        match gate_ticks {
+            None if self.phase => self.output.set_high(),
+            None => self.output.set_low(),
+            Some(gate_ticks) => {
+                if self.phase {
+                    self.output.set_high();
+                    self.gate_end = Some(position + gate_ticks);
+                }
+            }
+        }
+    }
+
+    /// Whether the pulse starting now is replaced by a burst, moves on to the
+    /// next pulse.
+    fn next_beat(&mut self) -> bool {
+        let burst = self.beat == 0;
+        self.beat = (self.beat + 1) % self.ratchet.every;
+        burst
+    }
+
+    /// Starts `ratchet.count` pulses spread evenly over `span` ticks from
+    /// `position`, half of each one high.
+    fn start_burst(&mut self, position: u32, span: u32) {
+        self.gate_end = None;
+        self.output.set_high();
+        self.burst_start = position;
+        self.burst_span = span;
+        self.burst_edges = 2 * self.ratchet.count - 1;
+    }
+
+    /// Toggles the output once the next edge of the burst is due.
+    fn update_burst(&mut self, position: u32) {
+        let edges = 2 * self.ratchet.count as u32;
+        let edge = edges - self.burst_edges as u32;
+        if position.wrapping_sub(self.burst_start) >= edge * self.burst_span / edges {
+            self.output.toggle();
+            self.burst_edges -= 1;
+        }
+    }
+
     pub fn update(&mut self, ticks: u32) {
         if self.previous_ticks > ticks {
-            self.reset_threshold()
-        } else if ticks >= self.threshold {
-            self.output.toggle();
-            self.threshold = self.threshold + self.threshold_interval;
+            self.next_bar()
+        } else {
+            let position = self.bar as u32 * self.bar_ticks + ticks;
+            if matches!(self.gate_end, Some(gate_end) if position >= gate_end) {
+                self.gate_end = None;
+                self.output.set_low();
+            }
+            if self.burst_edges > 0 {
+                self.update_burst(position);
+            }
+            let delay = self.swing_delay();
+            if position >= self.threshold + delay && self.mode != ChannelMode::ResetOut {
+                self.phase = !self.phase;
+                // a late pulse leaves less time until the next one
+                self.start_phase(position, 2 * self.threshold_interval - delay);
+                self.toggle = (self.toggle + 1) % 4;
+                self.advance_threshold();
+            }
         }
         self.previous_ticks = ticks;
         //self.output.toggle()
@@ -111,32 +593,453 @@ impl ClockChannel {
 
     pub fn set_led(&mut self, state: bool) {
         if state {
-            self.output.led_pin.set_high();
+            self.output.led_pin.set_high().ok();
         } else {
-            self.output.led_pin.set_low();
+            self.output.led_pin.set_low().ok();
         }
     }
 
     pub fn set_numerator(&mut self, numerator: u16) {
         self.prescaler.numerator = numerator;
     }
+    pub fn set_prescaler(&mut self, prescaler: Prescaler, bar_ticks: u32) {
+        self.prescaler = prescaler;
+        self.calculate_threshold(bar_ticks);
+    }
+    pub fn set_mode(&mut self, mode: ChannelMode) {
+        self.mode = mode;
+        self.gate_end = None;
+        self.burst_edges = 0;
+        self.output.set_low();
+    }
+    pub fn update_mode(&mut self, change: i8) {
+        self.set_mode(self.mode.step(change));
+    }
+    pub fn get_mode(&self) -> ChannelMode {
+        self.mode
+    }
+    pub fn set_swing(&mut self, swing: u8) {
+        self.swing = swing.clamp(MIN_SWING_PERCENT, MAX_SWING_PERCENT);
+    }
+    pub fn update_swing(&mut self, change: i8) {
+        let swing = self.swing as i16 + change as i16;
+        self.set_swing(swing.clamp(0, MAX_SWING_PERCENT as i16) as u8);
+    }
+    pub fn get_swing(&self) -> u8 {
+        self.swing
+    }
+    /// Restarts the random sequence deciding which pulses fire.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.rng = Rng::new(seed);
+    }
+    pub fn set_probability(&mut self, probability: u8) {
+        self.probability = probability.min(MAX_PROBABILITY_PERCENT);
+    }
+    pub fn update_probability(&mut self, change: i8) {
+        let probability = self.probability as i16 + change as i16;
+        self.set_probability(probability.clamp(0, MAX_PROBABILITY_PERCENT as i16) as u8);
+    }
+    pub fn get_probability(&self) -> u8 {
+        self.probability
+    }
+    pub fn set_offset(&mut self, offset: u8) {
+        self.offset = offset.min(MAX_OFFSET_PERCENT);
+    }
+    pub fn update_offset(&mut self, change: i8) {
+        let offset = self.offset as i16 + change as i16;
+        self.set_offset(offset.clamp(0, MAX_OFFSET_PERCENT as i16) as u8);
+    }
+    pub fn get_offset(&self) -> u8 {
+        self.offset
+    }
+    pub fn set_ratchet(&mut self, ratchet: Ratchet) {
+        self.ratchet = ratchet;
+        self.beat %= ratchet.every;
+    }
+    pub fn update_ratchet_count(&mut self, change: i8) {
+        self.set_ratchet(self.ratchet.step_count(change));
+    }
+    pub fn update_ratchet_every(&mut self, change: i8) {
+        self.set_ratchet(self.ratchet.step_every(change));
+    }
+    pub fn get_ratchet(&self) -> Ratchet {
+        self.ratchet
+    }
+    pub fn set_euclid(&mut self, euclid: Euclid) {
+        self.euclid = euclid;
+    }
+    pub fn update_steps(&mut self, change: i8) {
+        self.euclid = self.euclid.step_steps(change);
+    }
+    pub fn update_pulses(&mut self, change: i8) {
+        self.euclid = self.euclid.step_pulses(change);
+    }
+    pub fn update_rotation(&mut self, change: i8) {
+        self.euclid = self.euclid.step_rotation(change);
+    }
+    pub fn get_euclid(&self) -> Euclid {
+        self.euclid
+    }
+    pub fn set_gate_mode(&mut self, gate_mode: GateMode) {
+        self.gate_mode = gate_mode;
+        self.gate_end = None;
+    }
+    pub fn update_gate_mode(&mut self, change: i8) {
+        self.set_gate_mode(self.gate_mode.step(change));
+    }
+    pub fn get_gate_mode(&self) -> GateMode {
+        self.gate_mode
+    }
+    pub fn update_numerator(&mut self, change: i8, bar_ticks: u32) {
+        self.prescaler.numerator = step_ratio(self.prescaler.numerator, change);
+        self.calculate_threshold(bar_ticks);
+    }
+    pub fn get_numerator(&self) -> u16 {
+        self.prescaler.numerator
+    }
     pub fn update_denominator(&mut self, change: i8, bar_ticks: u32) {
-        if change < 0 {
-            if self.prescaler.denominator > 1 {
-                self.prescaler.denominator -= 1;
-            } else {
-                self.prescaler.denominator = 128;
-            }
-        } else if change > 0 {
-            if self.prescaler.denominator < 128 {
-                self.prescaler.denominator += 1;
-            } else {
-                self.prescaler.denominator = 1;
-            }
-        }
+        self.prescaler.denominator = step_ratio(self.prescaler.denominator, change);
         self.calculate_threshold(bar_ticks);
     }
     pub fn get_denominator(&self) -> u16 {
         self.prescaler.denominator
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPin;
+    use std::vec::Vec;
+
+    fn channel(denominator: u16, ticks_per_bar: u32) -> (ClockChannel<MockPin>, MockPin) {
+        ratio_channel(1, denominator, ticks_per_bar)
+    }
+
+    fn ratio_channel(
+        numerator: u16,
+        denominator: u16,
+        ticks_per_bar: u32,
+    ) -> (ClockChannel<MockPin>, MockPin) {
+        let output = MockPin::new();
+        let channel = ClockChannel::new(
+            MockPin::new(),
+            output.clone(),
+            Prescaler::new(numerator, denominator),
+            ticks_per_bar,
+        );
+        (channel, output)
+    }
+
+    /// Runs `bars` bars and returns the ticks since the first bar at which the
+    /// output changed.
+    fn edges(channel: &mut ClockChannel<MockPin>, output: &MockPin, bars: u32) -> Vec<u32> {
+        let ticks_per_bar = channel.bar_ticks;
+        channel.reset_threshold();
+        let mut edges = Vec::new();
+        for bar in 0..bars {
+            for ticks in 0..ticks_per_bar {
+                let level = output.is_high();
+                channel.update(ticks);
+                if output.is_high() != level {
+                    edges.push(bar * ticks_per_bar + ticks);
+                }
+            }
+        }
+        edges
+    }
+
+    #[test]
+    fn toggle_alternates_output() {
+        let led = MockPin::new();
+        let output = MockPin::new();
+        let mut clock_output = ClockOutput::new(led.clone(), output.clone());
+        clock_output.toggle();
+        assert!(output.is_high());
+        assert!(led.is_high());
+        clock_output.toggle();
+        assert!(!output.is_high());
+        assert!(!led.is_high());
+    }
+
+    #[test]
+    fn reset_threshold_starts_bar_high() {
+        let (mut channel, output) = channel(4, 1000);
+        channel.reset_threshold();
+        assert!(output.is_high());
+    }
+
+    #[test]
+    fn update_toggles_on_each_interval() {
+        let (mut channel, output) = channel(4, 1000);
+        channel.reset_threshold();
+        output.clear();
+        for ticks in 0..1000 {
+            channel.update(ticks);
+        }
+        assert_eq!(output.history(), [false, true, false]);
+    }
+
+    #[test]
+    fn update_resets_when_ticks_wrap() {
+        let (mut channel, output) = channel(4, 1000);
+        channel.reset_threshold();
+        channel.update(999);
+        output.clear();
+        channel.update(0);
+        assert_eq!(output.history(), [true]);
+    }
+
+    #[test]
+    fn uneven_divisions_land_on_the_ideal_grid() {
+        for denominator in [3, 7, 128] {
+            let ticks_per_bar = 10_000;
+            let (mut channel, output) = channel(denominator, ticks_per_bar);
+            let ideal: Vec<u32> = (1..denominator as u32)
+                .map(|k| k * ticks_per_bar / denominator as u32)
+                .collect();
+            assert_eq!(edges(&mut channel, &output, 1), ideal);
+            // the last interval ends exactly at the bar reset
+            assert_eq!(channel.threshold, ticks_per_bar);
+        }
+    }
+
+    #[test]
+    fn multiplied_ratio_spans_several_bars() {
+        // 3/2 of a bar between edges, realigned with the bar every 3 bars
+        let (mut channel, output) = ratio_channel(3, 2, 1000);
+        assert_eq!(edges(&mut channel, &output, 4), [1500, 3000]);
+    }
+
+    #[test]
+    fn trigger_mode_outputs_fixed_length_pulses() {
+        let (mut channel, output) = channel(4, 10_000);
+        channel.set_gate_mode(GateMode::Trigger(10));
+        let trigger = 10 * TICK_RATE / 1000;
+        // rising every other edge of the division, like the square wave
+        assert_eq!(
+            edges(&mut channel, &output, 1),
+            [trigger, 5000, 5000 + trigger]
+        );
+    }
+
+    #[test]
+    fn duty_mode_scales_with_the_period() {
+        let (mut channel, output) = channel(4, 10_000);
+        channel.set_gate_mode(GateMode::Duty(25));
+        assert_eq!(edges(&mut channel, &output, 1), [1250, 5000, 6250]);
+    }
+
+    #[test]
+    fn reset_out_triggers_every_bar() {
+        let (mut channel, output) = ratio_channel(3, 2, 10_000);
+        channel.set_mode(ChannelMode::ResetOut);
+        let trigger = RESET_TRIGGER_MS as u32 * TICK_RATE / 1000;
+        assert_eq!(
+            edges(&mut channel, &output, 2),
+            [trigger, 10_000, 10_000 + trigger]
+        );
+    }
+
+    #[test]
+    fn swing_delays_every_second_pulse() {
+        let (mut channel, output) = channel(8, 10_000);
+        channel.set_swing(60);
+        // a pulse every 2500 ticks, the second of each pair 500 ticks late
+        assert_eq!(
+            edges(&mut channel, &output, 1),
+            [1250, 3000, 4250, 5000, 6250, 8000, 9250]
+        );
+        channel.update_swing(100);
+        assert_eq!(channel.get_swing(), MAX_SWING_PERCENT);
+    }
+
+    #[test]
+    fn swung_gates_end_before_the_next_pulse() {
+        let (mut channel, output) = channel(8, 10_000);
+        channel.set_swing(MAX_SWING_PERCENT);
+        channel.set_gate_mode(GateMode::Duty(99));
+        let edges = edges(&mut channel, &output, 1);
+        assert_eq!(edges[..4], [2475, 3750, 4987, 5000]);
+    }
+
+    #[test]
+    fn euclid_mode_plays_the_hits_of_the_pattern() {
+        // E(3, 8) on sixteenths, `x..x..x.` twice per bar
+        let (mut channel, output) = channel(32, 10_000);
+        channel.set_mode(ChannelMode::Euclid);
+        channel.set_gate_mode(GateMode::Duty(50));
+        let edges = edges(&mut channel, &output, 1);
+        assert_eq!(edges[..6], [312, 1875, 2187, 3750, 4062, 5000]);
+        assert_eq!(edges.len(), 11);
+    }
+
+    #[test]
+    fn euclid_pattern_runs_across_bars() {
+        // `x..x.` on quarters, the second bar starts on the fifth step
+        let (mut channel, output) = channel(8, 10_000);
+        channel.set_mode(ChannelMode::Euclid);
+        channel.set_euclid(Euclid::new(5, 2, 0));
+        assert_eq!(
+            edges(&mut channel, &output, 2),
+            [1250, 7500, 8750, 12_500, 13_750]
+        );
+    }
+
+    #[test]
+    fn probability_drops_some_pulses() {
+        let edge_count = |probability| {
+            let (mut channel, output) = channel(32, 10_000);
+            channel.set_probability(probability);
+            channel.set_seed(3);
+            edges(&mut channel, &output, 20).len()
+        };
+        // two edges per pulse, the first rise comes with the reset
+        assert_eq!(edge_count(MAX_PROBABILITY_PERCENT), 20 * 32 - 1);
+        assert_eq!(edge_count(0), 0);
+        let half = edge_count(50);
+        assert!((240..400).contains(&half), "{}", half);
+    }
+
+    #[test]
+    fn probability_leaves_the_rests_alone() {
+        let (mut channel, output) = channel(32, 10_000);
+        channel.set_mode(ChannelMode::Euclid);
+        channel.set_gate_mode(GateMode::Duty(50));
+        channel.set_probability(50);
+        let edges = edges(&mut channel, &output, 4);
+        assert!(!edges.is_empty());
+        // only the hits of `x..x..x.` on sixteenths, and their ends
+        assert!(edges
+            .iter()
+            .all(|edge| [0, 312, 1875, 2187, 3750, 4062].contains(&(edge % 5000))));
+        channel.update_probability(100);
+        assert_eq!(channel.get_probability(), MAX_PROBABILITY_PERCENT);
+    }
+
+    #[test]
+    fn offset_shifts_the_pulses() {
+        // quarter notes shifted by an eighth
+        let (mut channel, output) = channel(8, 10_000);
+        channel.set_offset(50);
+        assert_eq!(
+            edges(&mut channel, &output, 2)[..9],
+            [1250, 2500, 3750, 5000, 6250, 7500, 8750, 10_000, 11_250]
+        );
+    }
+
+    #[test]
+    fn interval_percent_fits_the_longest_cycles() {
+        // 16/2 at 30 BPM is 640000 ticks a bar, an edge every 128 bars
+        let (mut channel, _) = ratio_channel(MAX_RATIO, 1, 640_000);
+        assert_eq!(channel.interval_percent(400), 4 * 128 * 640_000);
+        channel.set_offset(MAX_OFFSET_PERCENT);
+        channel.set_swing(MAX_SWING_PERCENT);
+        channel.reset_threshold();
+        assert_eq!(channel.threshold, 128 * 6400 * 98);
+        channel.toggle = 2;
+        assert_eq!(channel.swing_delay(), 128 * 640_000);
+    }
+
+    #[test]
+    fn late_offset_lets_the_last_pulse_play_out() {
+        let (mut channel, output) = channel(8, 10_000);
+        channel.set_offset(75);
+        let ideal: Vec<u32> = (0..15).map(|k| 1875 + k * 1250).collect();
+        assert_eq!(edges(&mut channel, &output, 2), ideal);
+    }
+
+    #[test]
+    fn late_offset_carries_the_remainder() {
+        // an edge every 1666.7 ticks, the first 2499 ticks in
+        let (mut channel, output) = channel(6, 10_000);
+        channel.set_offset(75);
+        let ideal: Vec<u32> = (0..11).map(|k| 2499 + k * 10_000 / 6).collect();
+        assert_eq!(edges(&mut channel, &output, 2), ideal);
+    }
+
+    #[test]
+    fn offset_delays_gates() {
+        let (mut channel, output) = channel(4, 10_000);
+        channel.set_gate_mode(GateMode::Trigger(10));
+        channel.set_offset(50);
+        assert_eq!(edges(&mut channel, &output, 1), [2500, 2600, 7500, 7600]);
+        channel.update_offset(100);
+        assert_eq!(channel.get_offset(), MAX_OFFSET_PERCENT);
+    }
+
+    #[test]
+    fn ratchet_bursts_in_place_of_every_pulse() {
+        let (mut channel, output) = channel(4, 10_000);
+        channel.set_mode(ChannelMode::Ratchet);
+        // three pulses over the first half of every 5000 tick period
+        assert_eq!(
+            edges(&mut channel, &output, 1),
+            [416, 833, 1250, 1666, 2083, 5000, 5416, 5833, 6250, 6666, 7083]
+        );
+    }
+
+    #[test]
+    fn ratchet_every_other_pulse() {
+        let (mut channel, output) = channel(4, 10_000);
+        channel.set_mode(ChannelMode::Ratchet);
+        channel.set_ratchet(Ratchet::new(2, 2));
+        channel.set_gate_mode(GateMode::Trigger(10));
+        assert_eq!(
+            edges(&mut channel, &output, 1),
+            [625, 1250, 1875, 5000, 5100]
+        );
+    }
+
+    #[test]
+    fn ratchet_steps_wrap_around() {
+        let ratchet = Ratchet::default().step_count(-2);
+        assert_eq!(ratchet.count(), MAX_RATCHET_COUNT);
+        assert_eq!(ratchet.step_every(-1).every(), MAX_RATCHET_EVERY);
+        assert_eq!(Ratchet::try_new(1, 1), None);
+        assert_eq!(Ratchet::try_new(2, 9), None);
+    }
+
+    #[test]
+    fn mode_steps_through_all_modes() {
+        assert_eq!(ChannelMode::Clock.step(1), ChannelMode::ResetOut);
+        assert_eq!(ChannelMode::Ratchet.step(1), ChannelMode::Clock);
+        assert_eq!(ChannelMode::Clock.step(-1), ChannelMode::Ratchet);
+    }
+
+    #[test]
+    fn gate_mode_steps_through_all_modes() {
+        assert_eq!(GateMode::Toggle.step(1), GateMode::Trigger(1));
+        assert_eq!(GateMode::Trigger(MAX_TRIGGER_MS).step(1), GateMode::Duty(1));
+        assert_eq!(GateMode::Duty(MAX_DUTY_PERCENT).step(1), GateMode::Toggle);
+        assert_eq!(GateMode::Toggle.step(-1), GateMode::Duty(MAX_DUTY_PERCENT));
+    }
+
+    #[test]
+    fn gate_mode_display() {
+        let digits = |mode: GateMode| [0, 1, 2, 3].map(|i| mode.display_digit(i));
+        assert_eq!(digits(GateMode::Toggle), [GLYPH_DASH, 5, 0, GLYPH_DASH]);
+        assert_eq!(digits(GateMode::Trigger(10)), [GLYPH_T, 0, 1, 0]);
+        assert_eq!(digits(GateMode::Duty(99)), [GLYPH_P, 0, 9, 9]);
+    }
+
+    #[test]
+    fn update_numerator_wraps_around() {
+        let (mut channel, _) = channel(4, 1000);
+        channel.update_numerator(-1, 1000);
+        assert_eq!(channel.get_numerator(), MAX_RATIO);
+        channel.update_numerator(1, 1000);
+        assert_eq!(channel.get_numerator(), 1);
+    }
+
+    #[test]
+    fn update_denominator_wraps_around() {
+        let (mut channel, _) = channel(1, 1000);
+        channel.update_denominator(-1, 1000);
+        assert_eq!(channel.get_denominator(), 128);
+        channel.update_denominator(1, 1000);
+        assert_eq!(channel.get_denominator(), 1);
+        channel.update_denominator(1, 1000);
+        assert_eq!(channel.get_denominator(), 2);
+    }
+}
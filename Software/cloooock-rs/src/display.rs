@@ -1,8 +1,7 @@
-use arduino_hal::{
-    delay_us,
-    port::{mode::Output, Pin},
+use embedded_hal::{
+    blocking::delay::DelayUs,
+    digital::v2::{OutputPin, PinState},
 };
-use embedded_hal::digital::v2::{OutputPin, PinState};
 
 const ZERO: u8 = 0b011000000;
 const ONE: u8 = 0b11111001;
@@ -14,19 +13,104 @@ const SIX: u8 = 0b10000010;
 const SEVEN: u8 = 0b11111000;
 const EIGHT: u8 = 0b10000000;
 const NINE: u8 = 0b10010000;
-const DIGITS: [u8; 10] = [ZERO, ONE, TWO, THREE, FOUR, FIVE, SIX, SEVEN, EIGHT, NINE];
-
-fn shift_out(byte: u8, sck_pin: &mut Pin<Output>, data_pin: &mut Pin<Output>) {
-    fn cycle(data: bool, sck_pin: &mut Pin<Output>, data_pin: &mut Pin<Output>) {
-        sck_pin.set_low();
-        data_pin.set_state(PinState::from(data));
-        delay_us(1);
-        sck_pin.set_high();
+const LETTER_D: u8 = 0b10100001;
+const LETTER_N: u8 = 0b10101011;
+const LETTER_T: u8 = 0b10000111;
+const LETTER_P: u8 = 0b10001100;
+const DASH: u8 = 0b10111111;
+const LETTER_C: u8 = 0b11000110;
+const LETTER_L: u8 = 0b11000111;
+const LETTER_SMALL_O: u8 = 0b10100011;
+const LETTER_SMALL_C: u8 = 0b10100111;
+const LETTER_R: u8 = 0b10101111;
+const LETTER_S: u8 = FIVE;
+const BLANK: u8 = 0b11111111;
+const LETTER_A: u8 = 0b10001000;
+const LETTER_E: u8 = 0b10000110;
+const LETTER_SMALL_U: u8 = 0b11100011;
+const LETTER_H: u8 = 0b10001001;
+const LETTER_SMALL_B: u8 = 0b10000011;
+
+/// Segment patterns indexed by [Displayable::display_digit]: the digits 0-9
+/// followed by the `GLYPH_*` letters.
+pub const GLYPHS: [u8; 27] = [
+    ZERO,
+    ONE,
+    TWO,
+    THREE,
+    FOUR,
+    FIVE,
+    SIX,
+    SEVEN,
+    EIGHT,
+    NINE,
+    LETTER_D,
+    LETTER_N,
+    LETTER_T,
+    LETTER_P,
+    DASH,
+    LETTER_C,
+    LETTER_L,
+    LETTER_SMALL_O,
+    LETTER_SMALL_C,
+    LETTER_R,
+    LETTER_S,
+    BLANK,
+    LETTER_A,
+    LETTER_E,
+    LETTER_SMALL_U,
+    LETTER_H,
+    LETTER_SMALL_B,
+];
+/// What each entry of [GLYPHS] reads as.
+pub const GLYPH_CHARS: [char; 27] = [
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'd', 'n', 't', 'P', '-', 'C', 'L', 'o', 'c',
+    'r', 'S', ' ', 'A', 'E', 'u', 'H', 'b',
+];
+pub const GLYPH_D: u8 = 10;
+pub const GLYPH_N: u8 = 11;
+pub const GLYPH_T: u8 = 12;
+pub const GLYPH_P: u8 = 13;
+pub const GLYPH_DASH: u8 = 14;
+pub const GLYPH_C: u8 = 15;
+pub const GLYPH_L: u8 = 16;
+pub const GLYPH_SMALL_O: u8 = 17;
+pub const GLYPH_SMALL_C: u8 = 18;
+pub const GLYPH_R: u8 = 19;
+pub const GLYPH_S: u8 = 20;
+pub const GLYPH_BLANK: u8 = 21;
+pub const GLYPH_A: u8 = 22;
+pub const GLYPH_E: u8 = 23;
+pub const GLYPH_SMALL_U: u8 = 24;
+pub const GLYPH_H: u8 = 25;
+pub const GLYPH_SMALL_B: u8 = 26;
+/// Set on a [Displayable::display_digit] to light the decimal point after
+/// the digit.
+pub const DECIMAL_POINT: u8 = 0x80;
+/// The decimal point segment of the [GLYPHS], lit when cleared.
+pub const SEGMENT_DP: u8 = 0b1000_0000;
+
+fn shift_out<P: OutputPin, D: DelayUs<u8>>(
+    byte: u8,
+    sck_pin: &mut P,
+    data_pin: &mut P,
+    delay: &mut D,
+) {
+    fn cycle<P: OutputPin, D: DelayUs<u8>>(
+        data: bool,
+        sck_pin: &mut P,
+        data_pin: &mut P,
+        delay: &mut D,
+    ) {
+        sck_pin.set_low().ok();
+        data_pin.set_state(PinState::from(data)).ok();
+        delay.delay_us(1);
+        sck_pin.set_high().ok();
     }
 
     for i in 0..8 {
         let bit = (byte >> (7 - i)) & 1;
-        cycle(bit != 0, sck_pin, data_pin)
+        cycle(bit != 0, sck_pin, data_pin, delay)
     }
 }
 
@@ -51,38 +135,67 @@ impl Displayable for u16 {
     }
 }
 
-pub struct Display {
-    clk_pin: Pin<Output>,
-    data_pin: Pin<Output>,
-    latch_pin: Pin<Output>,
+/// A value of up to three digits with a letter in front naming it, e.g. `d008`.
+pub struct Labelled {
+    label: u8,
+    value: u16,
+}
+
+impl Labelled {
+    pub fn new(label: u8, value: u16) -> Self {
+        Labelled { label, value }
+    }
+}
+
+impl Displayable for Labelled {
+    fn display_digit(&self, index: u8) -> u8 {
+        match index {
+            0 => self.label,
+            _ => self.value.display_digit(index),
+        }
+    }
+}
+
+pub struct Display<P: OutputPin, D: DelayUs<u8>> {
+    clk_pin: P,
+    data_pin: P,
+    latch_pin: P,
+    delay: D,
     index: u8,
 }
 
-impl Display {
-    pub fn new(clk_pin: Pin<Output>, data_pin: Pin<Output>, latch_pin: Pin<Output>) -> Self {
+impl<P: OutputPin, D: DelayUs<u8>> Display<P, D> {
+    pub fn new(clk_pin: P, data_pin: P, latch_pin: P, delay: D) -> Self {
         Display {
             clk_pin,
             data_pin,
             latch_pin,
+            delay,
             index: 0,
         }
     }
 
     pub fn update(&mut self, display_value: impl Displayable) {
         let display_byte: u8 = 0b0000_0001;
-        self.latch_pin.set_low();
+        self.latch_pin.set_low().ok();
         shift_out(
             display_byte << self.index,
             &mut self.clk_pin,
             &mut self.data_pin,
+            &mut self.delay,
         );
         let value = display_value.display_digit(self.index);
+        let mut segments = GLYPHS[(value & !DECIMAL_POINT) as usize];
+        if value & DECIMAL_POINT != 0 {
+            segments &= !SEGMENT_DP;
+        }
         shift_out(
-            DIGITS[value as usize],
+            segments,
             &mut self.clk_pin,
             &mut self.data_pin,
+            &mut self.delay,
         );
-        self.latch_pin.set_high();
+        self.latch_pin.set_high().ok();
         self.index += 1;
         if self.index > 3 {
             self.index = 0;
@@ -91,17 +204,78 @@ impl Display {
 
     pub fn debug(&mut self) {
         let display_byte: u8 = 0b0000_0001;
-        self.latch_pin.set_low();
-        shift_out(display_byte, &mut self.clk_pin, &mut self.data_pin);
+        self.latch_pin.set_low().ok();
         shift_out(
-            DIGITS[self.index as usize],
+            display_byte,
             &mut self.clk_pin,
             &mut self.data_pin,
+            &mut self.delay,
         );
-        self.latch_pin.set_high();
+        shift_out(
+            GLYPHS[self.index as usize],
+            &mut self.clk_pin,
+            &mut self.data_pin,
+            &mut self.delay,
+        );
+        self.latch_pin.set_high().ok();
         self.index += 1;
         if self.index > 1 {
             self.index = 0;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockPin, NoDelay};
+
+    fn to_byte(bits: &[bool]) -> u8 {
+        bits.iter().fold(0, |byte, &bit| (byte << 1) | bit as u8)
+    }
+
+    #[test]
+    fn update_shifts_digit_select_then_segments() {
+        let data = MockPin::new();
+        let latch = MockPin::new();
+        let mut display = Display::new(MockPin::new(), data.clone(), latch.clone(), NoDelay);
+
+        for index in 0..4u8 {
+            data.clear();
+            latch.clear();
+            display.update(1234u16);
+            let bits = data.history();
+            assert_eq!(bits.len(), 16);
+            assert_eq!(to_byte(&bits[..8]), 1 << index);
+            assert_eq!(to_byte(&bits[8..]), GLYPHS[index as usize + 1]);
+            assert_eq!(latch.history(), [false, true]);
+        }
+    }
+
+    #[test]
+    fn decimal_point_clears_its_segment() {
+        struct Point;
+        impl Displayable for Point {
+            fn display_digit(&self, _index: u8) -> u8 {
+                5 | DECIMAL_POINT
+            }
+        }
+        let data = MockPin::new();
+        let mut display = Display::new(MockPin::new(), data.clone(), MockPin::new(), NoDelay);
+        display.update(Point);
+        assert_eq!(to_byte(&data.history()[8..]), FIVE & !SEGMENT_DP);
+    }
+
+    #[test]
+    fn labelled_replaces_thousands() {
+        let labelled = Labelled::new(GLYPH_D, 128);
+        let digits: [u8; 4] = [0, 1, 2, 3].map(|i| labelled.display_digit(i));
+        assert_eq!(digits, [GLYPH_D, 1, 2, 8]);
+    }
+
+    #[test]
+    fn u16_digits() {
+        let digits: [u8; 4] = [0, 1, 2, 3].map(|i| 9051u16.display_digit(i));
+        assert_eq!(digits, [9, 0, 5, 1]);
+    }
+}
@@ -0,0 +1,31 @@
+/// Byte addressed non-volatile memory, e.g. the 1 KB ATmega328P EEPROM.
+pub trait Eeprom {
+    /// Size in bytes.
+    const CAPACITY: u16;
+
+    fn read_byte(&mut self, address: u16) -> u8;
+    fn write_byte(&mut self, address: u16, value: u8);
+
+    /// Whether [Self::write_byte] would return without waiting for a previous
+    /// write to finish.
+    fn is_ready(&mut self) -> bool {
+        true
+    }
+
+    fn read(&mut self, address: u16, buffer: &mut [u8]) {
+        for (offset, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_byte(address + offset as u16);
+        }
+    }
+
+    /// Writes `data` at `address`, skipping the bytes already holding the same
+    /// value, every write wears the cell.
+    fn update(&mut self, address: u16, data: &[u8]) {
+        for (offset, &value) in data.iter().enumerate() {
+            let address = address + offset as u16;
+            if self.read_byte(address) != value {
+                self.write_byte(address, value);
+            }
+        }
+    }
+}
@@ -1,57 +1,383 @@
-use arduino_hal::{
-    adc::{self, Channel},
-    clock::MHz16,
-    hal::usart::Usart0,
-    prelude::*,
-    Adc,
-};
+use ufmt::uWrite;
+use void::{ResultVoidExt, Void};
 
-pub struct Encoder<'a> {
-    adc: Adc,
-    clk_channel: &'a Channel,
-    dt_channel: &'a Channel,
-    last_clk: bool,
+use crate::time::TICK_RATE;
+
+/// Blocking analog reads, e.g. the ATmega328P ADC. The encoder contacts sit on
+/// ADC6/ADC7 which on the Nano are analog-only inputs.
+pub trait AnalogInput<C> {
+    fn read(&mut self, channel: &C) -> u16;
+}
+
+/// Quadrature transitions making up one detent, depends on the encoder part.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum StepsPerDetent {
+    One = 1,
+    Two = 2,
+    /// A full Gray code cycle per detent, resting with both contacts open.
+    #[default]
+    Four = 4,
+}
+
+/// Direction of the transition from the previous `(clk << 1) | dt` state to
+/// the current one, indexed by `previous << 2 | current`. Both contacts
+/// changing at once is a missed sample or bounce and counts as nothing.
+const TRANSITIONS: [i8; 16] = [
+    0, 1, -1, 0, // from 00
+    -1, 0, 0, 1, // from 01
+    1, 0, 0, -1, // from 10
+    0, -1, 1, 0, // from 11
+];
+
+/// State of both contacts open, the rest position of the detent.
+const REST: u8 = 0b11;
+
+/// Gray code decoder turning contact samples into detents.
+pub struct Decoder {
+    state: u8,
+    /// Transitions since the last reported detent.
+    steps: i8,
+    steps_per_detent: StepsPerDetent,
+}
+
+impl Decoder {
+    pub const fn new(steps_per_detent: StepsPerDetent) -> Self {
+        Decoder {
+            state: REST,
+            steps: 0,
+            steps_per_detent,
+        }
+    }
+
+    pub fn set_steps_per_detent(&mut self, steps_per_detent: StepsPerDetent) {
+        self.steps_per_detent = steps_per_detent;
+        self.steps = 0;
+    }
+
+    /// Decodes one pair of raw ADC readings, returns the direction of a
+    /// completed detent.
+    pub fn sample(&mut self, clk: u16, dt: u16) -> Option<i8> {
+        fn to_bool(x: u16) -> bool {
+            x > (1024u16 / 2)
+        }
+        self.decode(((to_bool(clk) as u8) << 1) | to_bool(dt) as u8)
+    }
+
+    fn decode(&mut self, state: u8) -> Option<i8> {
+        let step = TRANSITIONS[((self.state << 2) | state) as usize];
+        self.state = state;
+        self.steps += step;
+        let steps_per_detent = self.steps_per_detent as i8;
+        if self.steps.abs() >= steps_per_detent {
+            let direction = self.steps.signum();
+            self.steps = 0;
+            return Some(direction);
+        }
+        if state == REST && self.steps_per_detent == StepsPerDetent::Four {
+            // back at rest without a full cycle, the contacts bounced
+            self.steps = 0;
+        }
+        None
+    }
 }
 
-impl<'a> Encoder<'a> {
-    fn analog_read(adc: &mut Adc, channel: &adc::Channel) -> u16 {
-        adc.read_blocking(channel)
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new(StepsPerDetent::default())
     }
+}
 
-    pub fn new(adc: Adc, clk_channel: &'a Channel, dt_channel: &'a Channel) -> Self {
+/// The contact an [InterruptSampler] wants converted next.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Contact {
+    Clk,
+    Dt,
+}
+
+/// Feeds a [Decoder] from the ADC conversion complete interrupt, one
+/// conversion at a time, alternating between the CLK and DT channels.
+pub struct InterruptSampler {
+    decoder: Decoder,
+    clk: Option<u16>,
+}
+
+impl InterruptSampler {
+    pub const fn new(steps_per_detent: StepsPerDetent) -> Self {
+        InterruptSampler {
+            decoder: Decoder::new(steps_per_detent),
+            clk: None,
+        }
+    }
+
+    /// Channel to start the next conversion on.
+    pub fn next_contact(&self) -> Contact {
+        match self.clk {
+            None => Contact::Clk,
+            Some(_) => Contact::Dt,
+        }
+    }
+
+    /// Takes the result of the conversion started on [Self::next_contact],
+    /// returns a completed detent once both contacts are read.
+    pub fn conversion(&mut self, value: u16) -> Option<i8> {
+        match self.clk.take() {
+            None => {
+                self.clk = Some(value);
+                None
+            }
+            Some(clk) => self.decoder.sample(clk, value),
+        }
+    }
+}
+
+/// Blocking encoder reading both contacts on every [Self::poll], for the
+/// simulator and for logging the contacts with [Self::debug].
+pub struct Encoder<'a, A: AnalogInput<C>, C> {
+    adc: A,
+    clk_channel: &'a C,
+    dt_channel: &'a C,
+    decoder: Decoder,
+}
+
+impl<'a, A: AnalogInput<C>, C> Encoder<'a, A, C> {
+    fn analog_read(adc: &mut A, channel: &C) -> u16 {
+        adc.read(channel)
+    }
+
+    pub fn new(adc: A, clk_channel: &'a C, dt_channel: &'a C) -> Self {
         Encoder {
             adc,
             clk_channel,
             dt_channel,
-            last_clk: false,
+            decoder: Decoder::default(),
         }
     }
 
-    pub fn debug(&mut self, serial: &mut Usart0<MHz16>) {
+    pub fn set_steps_per_detent(&mut self, steps_per_detent: StepsPerDetent) {
+        self.decoder.set_steps_per_detent(steps_per_detent);
+    }
+
+    pub fn debug<W: uWrite<Error = Void>>(&mut self, serial: &mut W) {
         let clk = Self::analog_read(&mut self.adc, self.clk_channel);
         let dt = Self::analog_read(&mut self.adc, self.dt_channel);
         ufmt::uwriteln!(serial, "{} {}\r", clk, dt).void_unwrap();
     }
 
+    /// Samples the contacts, returns the direction of a completed detent.
     pub fn poll(&mut self) -> Option<i8> {
-        fn to_bool(x: u16) -> bool {
-            x > (1024u16 / 2)
+        let clk = Self::analog_read(&mut self.adc, self.clk_channel);
+        let dt = Self::analog_read(&mut self.adc, self.dt_channel);
+        self.decoder.sample(clk, dt)
+    }
+}
+
+/// One point of an [Acceleration] curve: detents arriving at most
+/// `max_interval` ticks apart count `multiplier` times.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AccelerationStep {
+    pub max_interval: u32,
+    pub multiplier: u8,
+}
+
+impl AccelerationStep {
+    pub const fn new(max_interval_ms: u32, multiplier: u8) -> Self {
+        AccelerationStep {
+            max_interval: max_interval_ms * TICK_RATE / 1000,
+            multiplier,
         }
-        let current_clk = Self::analog_read(&mut self.adc, self.clk_channel);
-        let current_dt = Self::analog_read(&mut self.adc, self.dt_channel);
-        let current_clk = to_bool(current_clk);
-        let current_dt = to_bool(current_dt);
+    }
+}
+
+/// Fast spins move tens of BPM per detent, anything slower than 100 ms
+/// between detents stays at ±1.
+pub const DEFAULT_CURVE: [AccelerationStep; 4] = [
+    AccelerationStep::new(10, 25),
+    AccelerationStep::new(25, 10),
+    AccelerationStep::new(50, 4),
+    AccelerationStep::new(100, 2),
+];
+
+/// Velocity sensitive scaling of [Encoder::poll] steps, timed with
+/// [crate::shared::Shared::timestamp].
+pub struct Acceleration<'a> {
+    /// Sorted by ascending `max_interval`, the first matching step applies.
+    curve: &'a [AccelerationStep],
+    previous_detent: Option<(u32, i8)>,
+}
 
-        // on falling edge
-        let ret_val = if !current_clk && self.last_clk {
-            match current_dt {
-                true => Some(-1),
-                false => Some(1),
+impl<'a> Acceleration<'a> {
+    pub const fn new(curve: &'a [AccelerationStep]) -> Self {
+        Acceleration {
+            curve,
+            previous_detent: None,
+        }
+    }
+
+    /// Scales the `step` polled at `now` by the speed of the turn. Changing
+    /// direction always starts slow.
+    pub fn accelerate(&mut self, step: i8, now: u32) -> i16 {
+        let multiplier = match self.previous_detent.replace((now, step.signum())) {
+            Some((previous, direction)) if direction == step.signum() => {
+                let interval = now.wrapping_sub(previous);
+                self.curve
+                    .iter()
+                    .find(|curve_step| interval <= curve_step.max_interval)
+                    .map_or(1, |curve_step| curve_step.multiplier)
             }
-        } else {
-            None
+            _ => 1,
         };
-        self.last_clk = current_clk;
-        ret_val
+        step as i16 * multiplier as i16
+    }
+}
+
+impl Default for Acceleration<'static> {
+    fn default() -> Self {
+        Self::new(&DEFAULT_CURVE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockAdc;
+
+    const CLK: u8 = 0;
+    const DT: u8 = 1;
+
+    fn poll_all(samples: &[(bool, bool)]) -> std::vec::Vec<Option<i8>> {
+        let mut encoder = Encoder::new(MockAdc::new(samples), &CLK, &DT);
+        samples.iter().map(|_| encoder.poll()).collect()
+    }
+
+    const CLOCKWISE: [(bool, bool); 4] =
+        [(true, false), (false, false), (false, true), (true, true)];
+
+    fn reverse(samples: &[(bool, bool)]) -> std::vec::Vec<(bool, bool)> {
+        samples.iter().map(|&(clk, dt)| (dt, clk)).collect()
+    }
+
+    fn detents(steps: &[Option<i8>]) -> std::vec::Vec<i8> {
+        steps.iter().flatten().copied().collect()
+    }
+
+    #[test]
+    fn full_cycle_is_one_detent() {
+        assert_eq!(poll_all(&CLOCKWISE), [None, None, None, Some(1)]);
+        assert_eq!(poll_all(&reverse(&CLOCKWISE)), [None, None, None, Some(-1)]);
+    }
+
+    #[test]
+    fn steps_per_detent() {
+        let samples: std::vec::Vec<_> = CLOCKWISE.iter().chain(&CLOCKWISE).copied().collect();
+        for (steps_per_detent, expected) in [
+            (StepsPerDetent::One, 8),
+            (StepsPerDetent::Two, 4),
+            (StepsPerDetent::Four, 2),
+        ] {
+            let mut encoder = Encoder::new(MockAdc::new(&samples), &CLK, &DT);
+            encoder.set_steps_per_detent(steps_per_detent);
+            let steps: std::vec::Vec<_> = samples.iter().map(|_| encoder.poll()).collect();
+            assert_eq!(detents(&steps), [1].repeat(expected));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_transitions() {
+        // 11 -> 00 skips a state, the direction cannot be told
+        let steps = poll_all(&[(false, false), (false, true), (true, true)]);
+        assert_eq!(detents(&steps), []);
+    }
+
+    #[test]
+    fn bounce_does_not_count() {
+        let steps = poll_all(&[
+            (true, false),
+            (true, true),
+            (true, false),
+            (false, false),
+            (true, false),
+            (false, false),
+            (false, true),
+            (true, true),
+        ]);
+        assert_eq!(detents(&steps), [1]);
+    }
+
+    /// Replays a log in the format of [Encoder::debug], one `clk dt` pair of
+    /// raw ADC readings per line.
+    fn replay(log: &str) -> std::vec::Vec<i8> {
+        let mut encoder = Encoder::new(MockAdc::from_log(log), &CLK, &DT);
+        let steps: std::vec::Vec<_> = log.lines().map(|_| encoder.poll()).collect();
+        detents(&steps)
+    }
+
+    #[test]
+    fn replays_clockwise_log() {
+        assert_eq!(replay(include_str!("../plot/clockwise.txt")), [1, 1, 1]);
+    }
+
+    #[test]
+    fn replays_bouncy_log() {
+        assert_eq!(
+            replay(include_str!("../plot/bouncy.txt")),
+            [-1, -1, -1, 1, 1, 1, -1]
+        );
+    }
+
+    /// `plot/encoder.txt` is not a sample log but the detents the old firmware
+    /// printed, `+1!` or `-1!` per line.
+    fn printed(log: &str) -> std::vec::Vec<i8> {
+        log.lines()
+            .map(|line| line.trim_end_matches('!').parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn bouncy_log_decodes_to_the_printed_detents() {
+        let printed = printed(include_str!("../plot/encoder.txt"));
+        assert_eq!(printed.len(), 7);
+        assert_eq!(replay(include_str!("../plot/bouncy.txt")), printed);
+    }
+
+    #[test]
+    fn interrupt_sampler_alternates_contacts() {
+        let mut sampler = InterruptSampler::new(StepsPerDetent::Four);
+        let mut detents = std::vec::Vec::new();
+        for &(clk, dt) in &CLOCKWISE {
+            assert_eq!(sampler.next_contact(), Contact::Clk);
+            assert_eq!(sampler.conversion(if clk { 1023 } else { 0 }), None);
+            assert_eq!(sampler.next_contact(), Contact::Dt);
+            detents.extend(sampler.conversion(if dt { 1023 } else { 0 }));
+        }
+        assert_eq!(detents, [1]);
+    }
+
+    #[test]
+    fn slow_turns_are_not_accelerated() {
+        let mut acceleration = Acceleration::default();
+        assert_eq!(acceleration.accelerate(1, 0), 1);
+        assert_eq!(acceleration.accelerate(1, 2000), 1);
+        assert_eq!(acceleration.accelerate(-1, 4000), -1);
+    }
+
+    #[test]
+    fn fast_turns_follow_the_curve() {
+        let mut acceleration = Acceleration::default();
+        assert_eq!(acceleration.accelerate(1, 0), 1);
+        assert_eq!(acceleration.accelerate(1, 80), 25);
+        assert_eq!(acceleration.accelerate(1, 280), 10);
+        assert_eq!(acceleration.accelerate(1, 880), 2);
+        // reversing starts over
+        assert_eq!(acceleration.accelerate(-1, 900), -1);
+        assert_eq!(acceleration.accelerate(-1, 1300), -4);
+    }
+
+    #[test]
+    fn custom_curve() {
+        let curve = [AccelerationStep::new(20, 100)];
+        let mut acceleration = Acceleration::new(&curve);
+        acceleration.accelerate(1, 0);
+        assert_eq!(acceleration.accelerate(1, 200), 100);
+        assert_eq!(acceleration.accelerate(1, 401), 1);
     }
 }
@@ -0,0 +1,118 @@
+/// Longest pattern selectable from the front panel.
+pub const MAX_STEPS: u8 = 32;
+
+/// Three hits in eight steps, `x..x..x.`.
+pub const DEFAULT: Euclid = Euclid::new(8, 3, 0);
+
+/// Euclidean rhythm: `pulses` hits spread as evenly as possible over `steps`
+/// steps, started `rotation` steps in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Euclid {
+    steps: u8,
+    pulses: u8,
+    rotation: u8,
+}
+
+impl Default for Euclid {
+    fn default() -> Self {
+        DEFAULT
+    }
+}
+
+impl Euclid {
+    /// Clamps `pulses` and wraps `rotation` to fit `steps`.
+    pub const fn new(steps: u8, pulses: u8, rotation: u8) -> Self {
+        let steps = if steps < 1 {
+            1
+        } else if steps > MAX_STEPS {
+            MAX_STEPS
+        } else {
+            steps
+        };
+        Euclid {
+            steps,
+            pulses: if pulses > steps { steps } else { pulses },
+            rotation: rotation % steps,
+        }
+    }
+
+    pub fn steps(&self) -> u8 {
+        self.steps
+    }
+
+    pub fn pulses(&self) -> u8 {
+        self.pulses
+    }
+
+    pub fn rotation(&self) -> u8 {
+        self.rotation
+    }
+
+    /// Whether `step` of the pattern is a hit. Bresenham's line spreads the
+    /// hits like Bjorklund's algorithm does, up to rotation.
+    pub fn is_hit(&self, step: u8) -> bool {
+        let step = (step % self.steps + self.rotation) % self.steps;
+        (step as u16 * self.pulses as u16) % (self.steps as u16) < self.pulses as u16
+    }
+
+    /// Steps through `1..=MAX_STEPS`, wrapping around.
+    pub fn step_steps(self, change: i8) -> Self {
+        let steps = wrap(self.steps, change, 1, MAX_STEPS);
+        Euclid::new(steps, self.pulses, self.rotation)
+    }
+
+    /// Steps through `0..=steps`, wrapping around.
+    pub fn step_pulses(self, change: i8) -> Self {
+        let pulses = wrap(self.pulses, change, 0, self.steps);
+        Euclid::new(self.steps, pulses, self.rotation)
+    }
+
+    /// Steps through `0..steps`, wrapping around.
+    pub fn step_rotation(self, change: i8) -> Self {
+        let rotation = wrap(self.rotation, change, 0, self.steps - 1);
+        Euclid::new(self.steps, self.pulses, rotation)
+    }
+}
+
+fn wrap(value: u8, change: i8, min: u8, max: u8) -> u8 {
+    let count = (max - min) as i16 + 1;
+    ((value - min) as i16 + change as i16).rem_euclid(count) as u8 + min
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(euclid: Euclid) -> std::string::String {
+        (0..euclid.steps())
+            .map(|step| if euclid.is_hit(step) { 'x' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn spreads_the_hits() {
+        assert_eq!(pattern(Euclid::new(8, 3, 0)), "x..x..x.");
+        assert_eq!(pattern(Euclid::new(16, 4, 0)), "x...x...x...x...");
+        assert_eq!(pattern(Euclid::new(5, 2, 0)), "x..x.");
+        assert_eq!(pattern(Euclid::new(4, 0, 0)), "....");
+        assert_eq!(pattern(Euclid::new(4, 4, 0)), "xxxx");
+    }
+
+    #[test]
+    fn rotation_starts_later_in_the_pattern() {
+        assert_eq!(pattern(Euclid::new(8, 3, 1)), "..x..x.x");
+        assert!(Euclid::new(8, 3, 1).is_hit(8 + 2));
+    }
+
+    #[test]
+    fn parameters_stay_within_the_steps() {
+        let euclid = Euclid::new(8, 8, 7).step_steps(-3);
+        assert_eq!(
+            (euclid.steps(), euclid.pulses(), euclid.rotation()),
+            (5, 5, 2)
+        );
+        assert_eq!(Euclid::new(1, 0, 0).step_steps(-1).steps(), MAX_STEPS);
+        assert_eq!(Euclid::new(4, 4, 0).step_pulses(1).pulses(), 0);
+        assert_eq!(Euclid::new(4, 0, 0).step_rotation(-1).rotation(), 3);
+    }
+}
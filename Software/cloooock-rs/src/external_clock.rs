@@ -0,0 +1,227 @@
+use crate::time::{TimeSignature, BPM, DEFAULT_TIME_SIGNATURE, TICK_RATE};
+
+/// Pulses per quarter note selectable for the clock input.
+pub const PPQN_CHOICES: [u8; 5] = [1, 2, 4, 24, 48];
+const DEFAULT_PPQN_INDEX: u8 = 3;
+
+/// Fixed point fraction bits of the smoothed period.
+const PERIOD_FRACTION_BITS: u32 = 4;
+/// The input counts as silent after this many periods without an edge.
+const TIMEOUT_PERIODS: u32 = 3;
+/// Longest accepted interval between two edges, 30 BPM at 1 PPQN.
+const MAX_INTERVAL: u32 = 2 * TICK_RATE;
+/// Shorter intervals are contact bounce or noise.
+const MIN_INTERVAL: u32 = 2;
+
+/// Measures the tempo of the clock input jack, or of MIDI timing clocks. Edges
+/// are timestamped with the free running tick counter, see
+/// [crate::shared::Shared::timestamp].
+pub struct ExternalClock {
+    ppqn_index: u8,
+    previous_edge: Option<u32>,
+    /// Smoothed ticks between edges, in 1/16 ticks. Zero until the second edge.
+    period: u32,
+    /// Edges since the start of the bar.
+    pulse: u16,
+    /// Pulse of the bar the next edge is, set by [Self::set_position].
+    next_pulse: Option<u16>,
+    time_signature: TimeSignature,
+}
+
+impl ExternalClock {
+    pub const fn new() -> Self {
+        ExternalClock {
+            ppqn_index: DEFAULT_PPQN_INDEX,
+            previous_edge: None,
+            period: 0,
+            pulse: 0,
+            next_pulse: None,
+            time_signature: DEFAULT_TIME_SIGNATURE,
+        }
+    }
+
+    pub fn ppqn(&self) -> u8 {
+        PPQN_CHOICES[self.ppqn_index as usize]
+    }
+
+    /// Counts the next pulse as the first of a bar, e.g. after a reset.
+    pub fn restart_bar(&mut self) {
+        self.pulse = 0;
+    }
+
+    /// Counts the next edge as `pulse` of the bar, zero starting it. Lets a
+    /// MIDI song position line the bars up with the song.
+    pub fn set_position(&mut self, pulse: u16) {
+        self.next_pulse = Some(pulse % self.bar_pulses());
+    }
+
+    /// Bars follow `signature` from the next edge on.
+    pub fn set_time_signature(&mut self, signature: TimeSignature) {
+        if signature != self.time_signature {
+            self.time_signature = signature;
+            self.pulse %= self.bar_pulses();
+        }
+    }
+
+    fn bar_pulses(&self) -> u16 {
+        self.time_signature.pulses_per_bar(self.ppqn() as u32) as u16
+    }
+
+    /// Steps through [PPQN_CHOICES], wrapping around.
+    pub fn update_ppqn(&mut self, change: i8) {
+        let count = PPQN_CHOICES.len() as i8;
+        self.ppqn_index = (self.ppqn_index as i8 + change).rem_euclid(count) as u8;
+        self.pulse = 0;
+    }
+
+    fn is_silent(&self, now: u32) -> bool {
+        match self.previous_edge {
+            None => true,
+            Some(previous) => {
+                let timeout = match self.period {
+                    0 => MAX_INTERVAL,
+                    period => TIMEOUT_PERIODS * (period >> PERIOD_FRACTION_BITS),
+                };
+                now.wrapping_sub(previous) > timeout
+            }
+        }
+    }
+
+    /// Registers a rising edge at `now`. Returns `true` when the edge starts a
+    /// bar, which is the first edge after silence and then every bar of the
+    /// time signature worth of edges.
+    pub fn edge(&mut self, now: u32) -> bool {
+        if self.is_silent(now) {
+            self.period = 0;
+            self.pulse = self.next_pulse.take().unwrap_or(0);
+            self.previous_edge = Some(now);
+            return self.pulse == 0;
+        }
+        let interval = now.wrapping_sub(self.previous_edge.unwrap_or(now));
+        if interval < MIN_INTERVAL {
+            return false;
+        }
+        self.previous_edge = Some(now);
+
+        let interval = interval << PERIOD_FRACTION_BITS;
+        self.period = match self.period {
+            0 => interval,
+            // exponential moving average smoothing out jitter of the source
+            period => (period * 3 + interval + 2) / 4,
+        };
+
+        self.pulse = match self.next_pulse.take() {
+            Some(pulse) => pulse,
+            None => (self.pulse + 1) % self.bar_pulses(),
+        };
+        self.pulse == 0
+    }
+
+    /// Tempo of the input, `None` until two edges arrived or once it went
+    /// silent.
+    pub fn bpm(&self, now: u32) -> Option<BPM> {
+        if self.period == 0 || self.is_silent(now) {
+            return None;
+        }
+        let ticks_per_beat = self.period * self.ppqn() as u32;
+        let tenths =
+            (((600 * TICK_RATE) << PERIOD_FRACTION_BITS) + ticks_per_beat / 2) / ticks_per_beat;
+        Some(BPM::from_tenths(tenths))
+    }
+}
+
+impl Default for ExternalClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `count` edges `interval` ticks apart starting at `start`, returns
+    /// the indices of the edges that started a bar.
+    fn feed(
+        clock: &mut ExternalClock,
+        start: u32,
+        interval: u32,
+        count: u32,
+    ) -> std::vec::Vec<u32> {
+        (0..count)
+            .filter(|&i| clock.edge(start + i * interval))
+            .collect()
+    }
+
+    #[test]
+    fn measures_bpm_from_edges() {
+        let mut clock = ExternalClock::new();
+        assert_eq!(clock.ppqn(), 24);
+        // 120 BPM at 24 PPQN is an edge every 208.3 ticks
+        let mut now = 0;
+        for i in 0..48u32 {
+            now = i * 10_000 / 48;
+            clock.edge(now);
+        }
+        assert_eq!(clock.bpm(now).map(|bpm| bpm.whole()), Some(120));
+    }
+
+    #[test]
+    fn measures_tenths_of_bpm() {
+        let mut clock = ExternalClock::new();
+        // 127.5 BPM at 24 PPQN is an edge every 196.1 ticks
+        let mut now = 0;
+        for i in 0..96u32 {
+            now = i * 6_000_000 / 30_600;
+            clock.edge(now);
+        }
+        assert_eq!(clock.bpm(now).map(|bpm| bpm.tenths), Some(1275));
+    }
+
+    #[test]
+    fn bar_starts_every_bar_of_pulses() {
+        let mut clock = ExternalClock::new();
+        clock.update_ppqn(-3);
+        assert_eq!(clock.ppqn(), 1);
+        assert_eq!(feed(&mut clock, 0, 5000, 5), [0, 2, 4]);
+    }
+
+    #[test]
+    fn silence_falls_back_and_restarts_the_bar() {
+        let mut clock = ExternalClock::new();
+        clock.update_ppqn(-3);
+        feed(&mut clock, 0, 5000, 3);
+        assert!(clock.bpm(10_000).is_some());
+        assert!(clock.bpm(10_000 + 3 * 5000 + 1).is_none());
+        assert!(clock.edge(40_000));
+        assert!(clock.bpm(40_000).is_none());
+    }
+
+    #[test]
+    fn position_moves_the_bar_start() {
+        let mut clock = ExternalClock::new();
+        clock.update_ppqn(-3);
+        clock.set_position(1);
+        assert_eq!(feed(&mut clock, 0, 5000, 4), [1, 3]);
+        clock.set_position(0);
+        assert!(clock.edge(20_000));
+    }
+
+    #[test]
+    fn bar_follows_the_time_signature() {
+        let mut clock = ExternalClock::new();
+        clock.update_ppqn(-2);
+        assert_eq!(clock.ppqn(), 2);
+        clock.set_time_signature(TimeSignature::try_new(7, 8).unwrap());
+        assert_eq!(feed(&mut clock, 0, 1000, 15), [0, 7, 14]);
+    }
+
+    #[test]
+    fn ignores_glitches() {
+        let mut clock = ExternalClock::new();
+        clock.update_ppqn(-3);
+        feed(&mut clock, 0, 5000, 2);
+        assert!(!clock.edge(5001));
+        assert_eq!(clock.bpm(5001).map(|bpm| bpm.whole()), Some(120));
+    }
+}
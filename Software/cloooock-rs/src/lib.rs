@@ -1,7 +1,25 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
+pub mod app;
+pub mod button;
+pub mod byte_queue;
 pub mod cv_output;
 pub mod display;
+pub mod eeprom;
 pub mod encoder;
+pub mod euclid;
+pub mod external_clock;
+pub mod midi;
+pub mod presets;
+pub mod rng;
+pub mod serial_cmd;
+pub mod settings;
+pub mod shared;
 pub mod state_machine;
+pub mod step_queue;
+pub mod storage;
+pub mod tap_tempo;
 pub mod time;
+
+#[cfg(test)]
+mod mock;
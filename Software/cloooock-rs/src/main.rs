@@ -2,49 +2,83 @@
 #![no_main]
 #![feature(abi_avr_interrupt)]
 
-use avr_device::atmega328p::TC1;
+use avr_device::atmega328p::{ADC, EEPROM, EXINT, PORTB, TC1, USART0};
 use avr_device::{atmega328p::tc1::tccr1b::CS1_A, interrupt::Mutex};
 
-use arduino_hal::{
-    adc::{self},
-    prelude::*,
-};
-use cloooock_rs::cv_output::ClockChannel;
-use cloooock_rs::state_machine::{ButtonPressed, DeviceState};
-use cloooock_rs::time::TicksPerBar;
+use arduino_hal::prelude::*;
+use cloooock_rs::app::{App, Input};
+use cloooock_rs::button::Button;
+use cloooock_rs::byte_queue::ByteQueue;
+use cloooock_rs::shared::{Shared, SharedAccess};
 use cloooock_rs::time::TICK_RATE;
 use core::cell::RefCell;
-use core::mem;
 use ufmt::{uWrite, uwriteln};
 
-use cloooock_rs::cv_output::Prescaler;
 use cloooock_rs::display::Display;
-use cloooock_rs::time::BPM;
-use cloooock_rs::encoder::Encoder;
+use cloooock_rs::eeprom::Eeprom;
+use cloooock_rs::encoder::{Contact, InterruptSampler, StepsPerDetent};
+use cloooock_rs::midi::{self, Received};
+use cloooock_rs::serial_cmd::{LineReader, Reply};
+use cloooock_rs::step_queue::StepQueue;
 use panic_halt as _;
 
-//const NUM_CHANNELS: u8 = 4;
-const MIN_BPM: u16 = 30;
-const MAX_BPM: u16 = 9999;
+/// The on-chip EEPROM, section 8.4 of the datasheet.
+struct AvrEeprom(EEPROM);
 
-struct ClockChannels {
-    bar_ticks: TicksPerBar,
-    channels: [ClockChannel; 4],
+impl Eeprom for AvrEeprom {
+    const CAPACITY: u16 = 1024;
+
+    fn is_ready(&mut self) -> bool {
+        self.0.eecr.read().eepe().bit_is_clear()
+    }
+
+    fn read_byte(&mut self, address: u16) -> u8 {
+        while !self.is_ready() {}
+        self.0.eear.write(|w| unsafe { w.bits(address) });
+        self.0.eecr.write(|w| w.eere().set_bit());
+        self.0.eedr.read().bits()
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        while !self.is_ready() {}
+        self.0.eear.write(|w| unsafe { w.bits(address) });
+        self.0.eedr.write(|w| unsafe { w.bits(value) });
+        // EEPE has to follow EEMPE within four cycles
+        avr_device::interrupt::free(|_| {
+            self.0.eecr.write(|w| w.eempe().set_bit());
+            self.0.eecr.write(|w| w.eepe().set_bit());
+        });
+    }
+}
+
+/// [SHARED] as seen from the main loop.
+struct CriticalSection;
+
+impl SharedAccess for CriticalSection {
+    fn with_shared<R>(&mut self, f: impl FnOnce(&mut Shared) -> R) -> R {
+        avr_device::interrupt::free(|cs| f(&mut SHARED.borrow(cs).borrow_mut()))
+    }
 }
 
 // global mutable state
-static TICKS: Mutex<RefCell<u32>> = Mutex::new(RefCell::new(0));
-static MASTER_BPM: Mutex<RefCell<BPM>> = Mutex::new(RefCell::new(BPM::new(120)));
-// output devices
-static mut CLOCK_CHANNELS: mem::MaybeUninit<ClockChannels> = mem::MaybeUninit::uninit();
+static SHARED: Mutex<RefCell<Shared>> = Mutex::new(RefCell::new(Shared::new()));
+// only touched by the ADC interrupt
+static ENCODER_SAMPLER: Mutex<RefCell<InterruptSampler>> =
+    Mutex::new(RefCell::new(InterruptSampler::new(StepsPerDetent::Four)));
+// detents from the ADC interrupt, drained by the main loop
+static ENCODER_STEPS: StepQueue = StepQueue::new();
+// bytes for the USART, sent by its data register empty interrupt with the
+// MIDI real-time bytes ahead of the replies
+static MIDI_OUT: ByteQueue<16> = ByteQueue::new();
+static TEXT_OUT: ByteQueue<128> = ByteQueue::new();
+// bytes received by the USART, drained by the main loop, each popped once it
+// was handled
+static SERIAL_IN: ByteQueue<64> = ByteQueue::new();
 
 #[arduino_hal::entry]
 fn main() -> ! {
-    let mut state = DeviceState::Running;
-    let mut selected_channel: i8 = 0;
     let dp = arduino_hal::Peripherals::take().unwrap();
     let pins = arduino_hal::pins!(dp);
-    let adc = arduino_hal::Adc::new(dp.ADC, Default::default());
 
     let mut serial = arduino_hal::default_serial!(dp, pins, 57600);
 
@@ -59,56 +93,53 @@ fn main() -> ! {
     let led_2 = pins.a2.into_output().downgrade();
     let led_3 = pins.a3.into_output().downgrade();
 
-    let pause_button = pins.a4.into_floating_input().downgrade();
-    let mut pause_button_was_pressed: bool;
-    let mut pause_button_previous_state = false;
+    let pause_pin = pins.a4.into_floating_input().downgrade();
+    let mut pause_button = Button::default();
 
     let output_0 = pins.d7.into_output().downgrade();
     let output_1 = pins.d10.into_output().downgrade();
     let output_2 = pins.d8.into_output().downgrade();
     let output_3 = pins.d9.into_output().downgrade();
-    // pinout
-    // let encoder_button = pins.d2.into_input().downgrade();
-    let encoder_dt_channel = &adc::channel::ADC6.into_channel();
-    let encoder_clk_channel = &adc::channel::ADC7.into_channel();
-    let encoder_button = pins.d2.into_floating_input().downgrade();
-    let mut encoder_button_was_pressed: bool;
-    let mut encoder_button_previous_state = false;
+
+    // Clock in D3              PD3 (INT1)
+    let _clock_input = pins.d3.into_floating_input();
+    // Reset in D12              PB4 (PCINT4)
+    let _reset_input = pins.d12.into_floating_input();
+    // Encoder DT  ADC6, CLK  ADC7, sampled by the ADC interrupt
+    let encoder_button_pin = pins.d2.into_floating_input().downgrade();
+    let mut encoder_button = Button::default();
 
     let display_latch_pin = pins.d4.into_output().downgrade();
     let display_clk_pin = pins.d5.into_output().downgrade();
     let display_data_pin = pins.d6.into_output().downgrade();
-    let mut display = Display::new(display_clk_pin, display_data_pin, display_latch_pin);
-
-    let mut encoder = Encoder::new(
-        adc,
-        encoder_clk_channel,
-        encoder_dt_channel,
+    let display = Display::new(
+        display_clk_pin,
+        display_data_pin,
+        display_latch_pin,
+        arduino_hal::Delay::new(),
     );
 
-    unsafe {
-        // SAFETY: Interrupts are not enabled at this point so we can safely write the global
-        // variable here.  A memory barrier afterwards ensures the compiler won't reorder this
-        // after any operation that enables interrupts.
-        avr_device::interrupt::free(|cs| {
-            let bpm_ref = MASTER_BPM.borrow(cs).borrow();
-            let ticks_per_bar = TicksPerBar::from(*bpm_ref).ticks;
-            CLOCK_CHANNELS = mem::MaybeUninit::new(ClockChannels {
-                bar_ticks: TicksPerBar::from(*bpm_ref),
-                channels: [
-                    ClockChannel::new(led_0, output_0, Prescaler::new(1, 2), ticks_per_bar),
-                    ClockChannel::new(led_1, output_1, Prescaler::new(1, 4), ticks_per_bar),
-                    ClockChannel::new(led_2, output_2, Prescaler::new(1, 6), ticks_per_bar),
-                    ClockChannel::new(led_3, output_3, Prescaler::new(1, 8), ticks_per_bar),
-                ],
-            });
-            core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
-        });
-    }
+    let mut app = App::new(
+        AvrEeprom(dp.EEPROM),
+        [
+            (led_0, output_0),
+            (led_1, output_1),
+            (led_2, output_2),
+            (led_3, output_3),
+        ],
+        display,
+    );
+    let mut shared = CriticalSection;
+    let mut commands = LineReader::new();
+    let mut midi_in = midi::Parser::new();
 
     // timers
     let tmr1: TC1 = dp.TC1;
     rig_timer1(&tmr1, &mut serial);
+    rig_clock_input(&dp.EXINT);
+    rig_reset_input(&dp.EXINT);
+    rig_encoder_adc(&dp.ADC);
+    rig_serial_input();
 
     ufmt::uwriteln!(&mut serial, "Start enable interrupts").void_unwrap();
     // Enable interrupts globally, not a replacement for the specific interrupt enable
@@ -120,143 +151,57 @@ fn main() -> ! {
     ufmt::uwriteln!(&mut serial, "Done enable interrupts").void_unwrap();
 
     loop {
-        if pause_button_previous_state && pause_button.is_high() {
-            pause_button_was_pressed = true;
-        } else {
-            pause_button_was_pressed = false;
+        // buttons pull low while pressed
+        let now = shared.with_shared(|shared| shared.timestamp);
+        let input = Input {
+            pause_button: pause_button.update(pause_pin.is_low(), now),
+            encoder_button: encoder_button.update(encoder_button_pin.is_low(), now),
+            pause_held: pause_button.is_pressed(),
+            encoder_held: encoder_button.is_pressed(),
+            encoder: ENCODER_STEPS.drain(),
+        };
+        app.step(input, &mut shared);
+        // due clocks wait in the app while the queue is full
+        while !MIDI_OUT.is_full() {
+            match app.midi_byte() {
+                Some(byte) => MIDI_OUT.push(byte),
+                None => break,
+            };
         }
-        pause_button_previous_state = pause_button.is_low();
-
-        if encoder_button_previous_state && encoder_button.is_high() {
-            encoder_button_was_pressed = true;
-        } else {
-            encoder_button_was_pressed = false;
-        }
-        encoder_button_previous_state = encoder_button.is_low();
-
-        match state {
-            DeviceState::Running => {
-                if let Some(change) = encoder.poll() {
-                    avr_device::interrupt::free(|cs| {
-                        let mut bpm_ref = MASTER_BPM.borrow(cs).borrow_mut();
-                        if !((bpm_ref.bpm <= MIN_BPM && change < 0)
-                            || (bpm_ref.bpm >= MAX_BPM && change > 0))
-                        {
-                            *bpm_ref = *bpm_ref + change;
-                        }
-
-                        unsafe {
-                            (&mut *CLOCK_CHANNELS.as_mut_ptr()).bar_ticks =
-                                TicksPerBar::from(*bpm_ref);
-                        }
-                        let state = unsafe { &mut *CLOCK_CHANNELS.as_mut_ptr() };
-                        let ticks_per_bar = TicksPerBar::from(*bpm_ref).ticks;
-                        for channel in state.channels.iter_mut() {
-                            channel.calculate_threshold(ticks_per_bar);
-                        }
-                    });
-                }
-                avr_device::interrupt::free(|cs| {
-                    //let bpm_ref = MASTER_BPM.borrow(cs).borrow();
-                    let mut ticks_ref = TICKS.borrow(cs).borrow_mut();
-                    let state = unsafe { &mut *CLOCK_CHANNELS.as_mut_ptr() };
-                    if *ticks_ref >= state.bar_ticks.ticks {
-                        *ticks_ref = 0;
-                        for channel in state.channels.iter_mut() {
-                            channel.reset_threshold();
-                        }
-                    }
-                    for channel in state.channels.iter_mut() {
-                        channel.update(*ticks_ref);
-                    }
-                });
-                avr_device::interrupt::free(|cs| {
-                    let bpm_ref = MASTER_BPM.borrow(cs).borrow();
-                    display.update(*bpm_ref);
-                });
-                if pause_button_was_pressed {
-                    //ufmt::uwriteln!(&mut serial, "Pausing").void_unwrap();
-                    state = state.transition(ButtonPressed::PauseButton);
-                }
-                if encoder_button_was_pressed {
-                    let clock_channels = unsafe { &mut *CLOCK_CHANNELS.as_mut_ptr() };
-                    for channel in clock_channels.channels.iter_mut() {
-                        channel.set_led(false);
-                    }
-                    clock_channels.channels[selected_channel as usize].set_led(true);
-                    state = state.transition(ButtonPressed::EncoderButton);
-                }
-            }
 
-            DeviceState::Paused => {
-                if pause_button_was_pressed {
-                    state = state.transition(ButtonPressed::PauseButton);
-                }
-                avr_device::interrupt::free(|cs| {
-                    let bpm_ref = MASTER_BPM.borrow(cs).borrow();
-                    display.update(*bpm_ref);
-                });
-            }
-
-            DeviceState::SelectingChannel => {
-                let clock_channels = unsafe { &mut *CLOCK_CHANNELS.as_mut_ptr() };
-                if let Some(change) = encoder.poll() {
-                    selected_channel += change;
-                    if selected_channel > 3 {
-                        selected_channel = 0;
-                    } else if selected_channel < 0 {
-                        selected_channel = 3;
+        while let Some(byte) = SERIAL_IN.peek() {
+            match midi_in.receive(byte) {
+                Received::Message(message) => app.receive_midi(message, &mut shared),
+                Received::Midi => {}
+                Received::Text(byte) => {
+                    if let Some(command) = commands.push(byte) {
+                        let reply = match command {
+                            Ok(command) => app.execute(command, &mut shared),
+                            Err(error) => Reply::Error(error),
+                        };
+                        // a reply that does not fit is cut short
+                        let _ = reply.write(&mut &TEXT_OUT);
                     }
-                    for channel in clock_channels.channels.iter_mut() {
-                        channel.set_led(false);
-                    }
-                    clock_channels.channels[selected_channel as usize].set_led(true);
-                }
-                if encoder_button_was_pressed {
-                    for channel in clock_channels.channels.iter_mut() {
-                        channel.set_led(false);
-                    }
-                    clock_channels.channels[selected_channel as usize].set_led(true);
-                    state = state.transition(ButtonPressed::EncoderButton);
-                }
-                if pause_button_was_pressed {
-                    for channel in clock_channels.channels.iter_mut() {
-                        channel.reset_threshold();
-                    }
-                    state = state.transition(ButtonPressed::PauseButton);
-                }
-                avr_device::interrupt::free(|cs| {
-                    let bpm_ref = MASTER_BPM.borrow(cs).borrow();
-                    display.update(*bpm_ref);
-                });
-            }
-            DeviceState::SettingDivisionState => {
-                let clock_channels = unsafe { &mut *CLOCK_CHANNELS.as_mut_ptr() };
-                if let Some(change) = encoder.poll() {
-                    clock_channels.channels[selected_channel as usize].set_led(true);
-                    clock_channels.channels[selected_channel as usize]
-                        .update_denominator(change, clock_channels.bar_ticks.ticks);
-                }
-                display
-                    .update(clock_channels.channels[selected_channel as usize].get_denominator());
-                if encoder_button_was_pressed {
-                    for channel in clock_channels.channels.iter_mut() {
-                        channel.set_led(false);
-                    }
-                    clock_channels.channels[selected_channel as usize].set_led(true);
-                    state = state.transition(ButtonPressed::EncoderButton);
-                }
-                if pause_button_was_pressed {
-                    for channel in clock_channels.channels.iter_mut() {
-                        channel.reset_threshold();
-                    }
-                    state = state.transition(ButtonPressed::PauseButton);
                 }
             }
+            SERIAL_IN.pop();
+        }
+        if !MIDI_OUT.is_empty() || !TEXT_OUT.is_empty() {
+            start_sending();
         }
     }
 }
 
+/// Lets [USART_UDRE] send the queued bytes, it stops once they are sent.
+fn start_sending() {
+    avr_device::interrupt::free(|_| {
+        // SAFETY: the HAL no longer reads or writes the USART, UCSR0B is only
+        // modified with interrupts disabled
+        let usart = unsafe { &*USART0::ptr() };
+        usart.ucsr0b.modify(|_, w| w.udrie0().set_bit());
+    });
+}
+
 pub const fn calc_overflow(clock_hz: u32, target_hz: u32, prescale: u32) -> u32 {
     /*
     https://github.com/Rahix/avr-hal/issues/75
@@ -307,11 +252,116 @@ fn rig_timer1<W: uWrite<Error = void::Void>>(tmr1: &TC1, serial: &mut W) {
     tmr1.timsk1.write(|w| w.ocie1a().set_bit()); //enable this specific interrupt
 }
 
+fn rig_clock_input(exint: &EXINT) {
+    // section 13.2, INT1 on the rising edge
+    exint.eicra.modify(|_, w| w.isc1().bits(0b11));
+    exint
+        .eimsk
+        .modify(|r, w| w.int().bits(r.int().bits() | 0b10));
+}
+
+fn rig_serial_input() {
+    // section 20.11.3, unmask the receive complete interrupt
+    // SAFETY: interrupts are still disabled, nothing else touches UCSR0B
+    let usart = unsafe { &*USART0::ptr() };
+    usart.ucsr0b.modify(|_, w| w.rxcie0().set_bit());
+}
+
+fn rig_reset_input(exint: &EXINT) {
+    // section 13.2.4, pin change interrupt 0 covers PB0..PB7, unmask PCINT4 only
+    exint
+        .pcmsk0
+        .modify(|r, w| w.pcint().bits(r.pcint().bits() | 0b1_0000));
+    exint
+        .pcicr
+        .modify(|r, w| w.pcie().bits(r.pcie().bits() | 0b001));
+}
+
+/// Points the ADC at the next encoder contact and starts a conversion.
+fn start_encoder_conversion(adc: &avr_device::atmega328p::adc::RegisterBlock, contact: Contact) {
+    adc.admux.write(|w| {
+        let w = w.refs().avcc();
+        match contact {
+            Contact::Clk => w.mux().adc7(),
+            Contact::Dt => w.mux().adc6(),
+        }
+    });
+    adc.adcsra.modify(|_, w| w.adsc().set_bit());
+}
+
+fn rig_encoder_adc(adc: &ADC) {
+    // section 24.9, 16 MHz / 128 gives about 9600 conversions per second,
+    // one contact each, chained from the conversion complete interrupt
+    adc.adcsra
+        .write(|w| w.aden().set_bit().adie().set_bit().adps().prescaler_128());
+    start_encoder_conversion(adc, Contact::Clk);
+}
+
 #[avr_device::interrupt(atmega328p)]
 fn TIMER1_COMPA() {
     avr_device::interrupt::free(|cs| {
         // Interrupts are disabled here
-        let mut ticks_ref = TICKS.borrow(cs).borrow_mut();
-        *ticks_ref += 1;
+        SHARED.borrow(cs).borrow_mut().timer_tick();
+    });
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn INT1() {
+    avr_device::interrupt::free(|cs| {
+        SHARED.borrow(cs).borrow_mut().clock_edge();
+    });
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn PCINT0() {
+    // fires on both edges, only the rising one resets
+    // SAFETY: reading the input register has no side effects
+    let rising = unsafe { (*PORTB::ptr()).pinb.read().pb4().bit_is_set() };
+    if rising {
+        avr_device::interrupt::free(|cs| {
+            SHARED.borrow(cs).borrow_mut().reset_edge();
+        });
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn USART_RX() {
+    // SAFETY: reading UDR0 is only done here
+    let usart = unsafe { &*USART0::ptr() };
+    let byte = usart.udr0.read().bits();
+    // timing clocks are timestamped here like the clock input, unless the
+    // bytes before them are still being handled, e.g. a start that the first
+    // clock after it has to follow
+    if byte == midi::TIMING_CLOCK && SERIAL_IN.is_empty() {
+        avr_device::interrupt::free(|cs| {
+            SHARED.borrow(cs).borrow_mut().midi_clock_edge();
+        });
+    } else {
+        // dropped if the main loop falls this far behind
+        SERIAL_IN.push(byte);
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn USART_UDRE() {
+    // SAFETY: see start_sending
+    let usart = unsafe { &*USART0::ptr() };
+    match MIDI_OUT.pop().or_else(|| TEXT_OUT.pop()) {
+        Some(byte) => usart.udr0.write(|w| unsafe { w.bits(byte) }),
+        None => usart.ucsr0b.modify(|_, w| w.udrie0().clear_bit()),
+    }
+}
+
+#[avr_device::interrupt(atmega328p)]
+fn ADC() {
+    // SAFETY: the ADC is only driven from here once rigged
+    let adc = unsafe { &*ADC::ptr() };
+    let value = adc.adc.read().bits();
+    avr_device::interrupt::free(|cs| {
+        let mut sampler = ENCODER_SAMPLER.borrow(cs).borrow_mut();
+        if let Some(step) = sampler.conversion(value) {
+            ENCODER_STEPS.push(step);
+        }
+        start_encoder_conversion(adc, sampler.next_contact());
     });
 }
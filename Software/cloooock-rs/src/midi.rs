@@ -0,0 +1,311 @@
+use crate::time::{TimeSignature, DEFAULT_TIME_SIGNATURE};
+
+pub const TIMING_CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const SONG_POSITION: u8 = 0xF2;
+
+/// Timing clocks per quarter note, fixed by the MIDI specification.
+pub const PPQN: u32 = 24;
+/// Timing clocks per unit of the song position, a sixteenth note.
+const CLOCKS_PER_SONG_BEAT: u32 = 6;
+
+/// Messages understood by [Parser].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Message {
+    TimingClock,
+    Start,
+    Continue,
+    Stop,
+    /// Sixteenth notes since the start of the song.
+    SongPosition(u16),
+}
+
+impl Message {
+    /// Timing clock of the bar of `signature` a song position points at, see
+    /// [crate::external_clock::ExternalClock::set_position].
+    pub fn bar_clock(song_position: u16, signature: TimeSignature) -> u16 {
+        let clocks_per_bar = signature.pulses_per_bar(PPQN);
+        (song_position as u32 * CLOCKS_PER_SONG_BEAT % clocks_per_bar) as u16
+    }
+}
+
+/// What [Parser::receive] made of a byte.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Received {
+    Message(Message),
+    /// Part of a message, or one Cloooock ignores.
+    Midi,
+    /// Not MIDI, a character of a serial command.
+    Text(u8),
+}
+
+const SYSTEM_EXCLUSIVE: u8 = 0xF0;
+const END_OF_EXCLUSIVE: u8 = 0xF7;
+
+/// Data bytes following `status`, system exclusive runs until its end.
+fn data_len(status: u8) -> u8 {
+    match status {
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        0x80..=0xBF | 0xE0..=0xEF | SONG_POSITION => 2,
+        _ => 0,
+    }
+}
+
+/// Picks the MIDI messages out of the bytes received on the USART. The
+/// serial commands are plain ASCII, so the status bytes are MIDI and so are
+/// the data bytes they announce. Channel messages keep their running status
+/// until the next system message, any text in between counts as their data.
+#[derive(Default)]
+pub struct Parser {
+    /// Status of the message being received, `None` when data bytes are text.
+    status: Option<u8>,
+    /// Data bytes of the message received so far.
+    data: [u8; 2],
+    count: u8,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            status: None,
+            data: [0; 2],
+            count: 0,
+        }
+    }
+
+    pub fn receive(&mut self, byte: u8) -> Received {
+        let message = match byte {
+            // real-time messages may come between any two bytes
+            TIMING_CLOCK => Message::TimingClock,
+            START => Message::Start,
+            CONTINUE => Message::Continue,
+            STOP => Message::Stop,
+            0xF9 | 0xFD..=0xFF => return Received::Midi,
+            0x80..=END_OF_EXCLUSIVE => {
+                let announces_data = byte == SYSTEM_EXCLUSIVE || data_len(byte) > 0;
+                self.status = announces_data.then_some(byte);
+                self.count = 0;
+                return Received::Midi;
+            }
+            _ => match self.status {
+                None => return Received::Text(byte),
+                Some(SYSTEM_EXCLUSIVE) => return Received::Midi,
+                Some(status) => {
+                    self.data[self.count as usize] = byte;
+                    self.count += 1;
+                    if self.count < data_len(status) {
+                        return Received::Midi;
+                    }
+                    self.count = 0;
+                    if status < SYSTEM_EXCLUSIVE {
+                        // running status, more data may follow
+                        return Received::Midi;
+                    }
+                    self.status = None;
+                    match status {
+                        SONG_POSITION => {
+                            let [lsb, msb] = self.data;
+                            Message::SongPosition((msb as u16) << 7 | lsb as u16)
+                        }
+                        _ => return Received::Midi,
+                    }
+                }
+            },
+        };
+        Received::Message(message)
+    }
+}
+
+/// MIDI real-time messages following the bar the channels play, sent on the
+/// USART next to the serial commands. The message bytes are above anything
+/// ASCII, so they interleave with the replies without confusing either.
+pub struct ClockOut {
+    /// Clocks already due in the current bar.
+    clock: u32,
+    previous_position: u32,
+    /// Clocks due but not sent yet.
+    pending_clocks: u8,
+    /// Start, stop or continue waiting to be sent ahead of the clocks.
+    transport: Option<u8>,
+    running: bool,
+    clocks_per_bar: u32,
+}
+
+impl ClockOut {
+    /// Starts with a start message, the module boots running.
+    pub const fn new() -> Self {
+        ClockOut {
+            clock: 0,
+            previous_position: 0,
+            pending_clocks: 0,
+            transport: Some(START),
+            running: true,
+            clocks_per_bar: DEFAULT_TIME_SIGNATURE.pulses_per_bar(PPQN),
+        }
+    }
+
+    pub fn set_time_signature(&mut self, signature: TimeSignature) {
+        self.clocks_per_bar = signature.pulses_per_bar(PPQN);
+    }
+
+    /// Follows pausing and resuming with stop and continue, clocks not sent
+    /// yet are dropped on stop. A start not sent yet resumes as well.
+    pub fn set_running(&mut self, running: bool) {
+        if running != self.running {
+            self.running = running;
+            if !running {
+                self.pending_clocks = 0;
+                self.transport = Some(STOP);
+            } else if self.transport != Some(START) {
+                self.transport = Some(CONTINUE);
+            }
+        }
+    }
+
+    /// The bar restarts from the top, followers restart their song too.
+    pub fn restart(&mut self) {
+        self.clock = 0;
+        self.previous_position = 0;
+        self.pending_clocks = 0;
+        self.transport = Some(START);
+    }
+
+    /// Call with the position in the bar whenever the channels are updated,
+    /// a position before the previous one starts the next bar.
+    pub fn update(&mut self, position: u32, bar_ticks: u32) {
+        if position < self.previous_position {
+            self.clock = 0;
+        }
+        self.previous_position = position;
+        while self.clock < self.clocks_per_bar
+            && position >= self.clock * bar_ticks / self.clocks_per_bar
+        {
+            self.clock += 1;
+            self.pending_clocks = self.pending_clocks.saturating_add(1);
+        }
+    }
+
+    /// The next byte to send, call until it returns `None`.
+    pub fn next_byte(&mut self) -> Option<u8> {
+        if let Some(transport) = self.transport.take() {
+            return Some(transport);
+        }
+        if self.pending_clocks > 0 {
+            self.pending_clocks -= 1;
+            return Some(TIMING_CLOCK);
+        }
+        None
+    }
+}
+
+impl Default for ClockOut {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(clock: &mut ClockOut) -> Vec<u8> {
+        core::iter::from_fn(|| clock.next_byte()).collect()
+    }
+
+    #[test]
+    fn picks_messages_out_of_commands() {
+        let mut parser = Parser::new();
+        let received: Vec<_> = [b'b', TIMING_CLOCK, b'p', SONG_POSITION, 0x10, 0x01, b'm']
+            .iter()
+            .map(|&byte| parser.receive(byte))
+            .collect();
+        assert_eq!(
+            received,
+            [
+                Received::Text(b'b'),
+                Received::Message(Message::TimingClock),
+                Received::Text(b'p'),
+                Received::Midi,
+                Received::Midi,
+                Received::Message(Message::SongPosition(0x90)),
+                Received::Text(b'm'),
+            ]
+        );
+        // a song position in the second bar, one beat in
+        assert_eq!(Message::bar_clock(8 + 4, DEFAULT_TIME_SIGNATURE), 24);
+        let seven_eight = TimeSignature::try_new(7, 8).unwrap();
+        assert_eq!(Message::bar_clock(7 * 2 + 1, seven_eight), 6);
+    }
+
+    #[test]
+    fn swallows_the_data_of_other_messages() {
+        let mut parser = Parser::new();
+        let mut text = |bytes: &[u8]| -> Vec<u8> {
+            bytes
+                .iter()
+                .filter_map(|&byte| match parser.receive(byte) {
+                    Received::Text(byte) => Some(byte),
+                    _ => None,
+                })
+                .collect()
+        };
+        // a note on whose data looks like line ends
+        assert_eq!(text(&[0x90, 0x0A, 0x0D]), []);
+        // running status, with a clock in between
+        assert_eq!(text(&[0x0A, TIMING_CLOCK, 0x0D, 0xC0, 0x0A, 0x0D]), []);
+        assert_eq!(
+            text(&[0xF0, b'r', b'u', b'n', END_OF_EXCLUSIVE, b'\n']),
+            b"\n"
+        );
+        assert_eq!(text(&[0xF3, 0x0D, b'\n']), b"\n");
+    }
+
+    #[test]
+    fn sends_24_clocks_per_beat() {
+        let mut clock = ClockOut::new();
+        let bar_ticks = 10_000;
+        let mut sent = Vec::new();
+        for bar in 0..3 {
+            for position in 0..bar_ticks {
+                clock.update(position, bar_ticks);
+                sent.extend(drain(&mut clock));
+            }
+            let clocks = sent.iter().filter(|&&byte| byte == TIMING_CLOCK).count();
+            assert_eq!(clocks as u32, (bar + 1) * PPQN * 2);
+        }
+        assert_eq!(sent[..2], [START, TIMING_CLOCK]);
+    }
+
+    #[test]
+    fn follows_the_time_signature() {
+        let mut clock = ClockOut::new();
+        clock.set_time_signature(TimeSignature::try_new(7, 8).unwrap());
+        for position in 0..17_500 {
+            clock.update(position, 17_500);
+        }
+        let clocks = drain(&mut clock).len() - 1;
+        assert_eq!(clocks, 84);
+    }
+
+    #[test]
+    fn transport_goes_ahead_of_the_clocks() {
+        let mut clock = ClockOut::new();
+        clock.update(0, 10_000);
+        clock.set_running(false);
+        assert_eq!(drain(&mut clock), [STOP]);
+        clock.set_running(false);
+        assert_eq!(drain(&mut clock), []);
+        clock.set_running(true);
+        clock.restart();
+        assert_eq!(drain(&mut clock), [START]);
+        clock.set_running(false);
+        clock.set_running(true);
+        assert_eq!(drain(&mut clock), [CONTINUE]);
+        clock.set_running(false);
+        clock.restart();
+        clock.set_running(true);
+        assert_eq!(drain(&mut clock), [START]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Host-side stand-ins for the AVR peripherals, used by the unit tests.
+
+use core::{cell::RefCell, convert::Infallible};
+use std::{rc::Rc, vec::Vec};
+
+use embedded_hal::{blocking::delay::DelayUs, digital::v2::OutputPin};
+
+/// Output pin recording every level it is driven to. Clones share the same
+/// history so a test can keep a handle after moving the pin into a driver.
+#[derive(Clone, Default)]
+pub struct MockPin {
+    history: Rc<RefCell<Vec<bool>>>,
+}
+
+impl MockPin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.history.borrow().last().copied().unwrap_or(false)
+    }
+
+    pub fn history(&self) -> Vec<bool> {
+        self.history.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.history.borrow_mut().clear();
+    }
+}
+
+impl OutputPin for MockPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.history.borrow_mut().push(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.history.borrow_mut().push(true);
+        Ok(())
+    }
+}
+
+pub struct NoDelay;
+
+impl DelayUs<u8> for NoDelay {
+    fn delay_us(&mut self, _us: u8) {}
+}
+
+/// ADC returning scripted samples, one per [crate::encoder::AnalogInput::read]
+/// call. Channel `0` is CLK and channel `1` is DT.
+pub struct MockAdc {
+    samples: Vec<(u16, u16)>,
+    position: usize,
+}
+
+impl MockAdc {
+    pub fn new(samples: &[(bool, bool)]) -> Self {
+        let level = |high: bool| if high { 1023 } else { 0 };
+        MockAdc {
+            samples: samples
+                .iter()
+                .map(|&(clk, dt)| (level(clk), level(dt)))
+                .collect(),
+            position: 0,
+        }
+    }
+
+    /// Raw readings from a log of `clk dt` lines as printed by
+    /// [crate::encoder::Encoder::debug].
+    pub fn from_log(log: &str) -> Self {
+        MockAdc {
+            samples: log
+                .lines()
+                .map(|line| {
+                    let mut values = line.split_whitespace().map(|value| value.parse().unwrap());
+                    (values.next().unwrap(), values.next().unwrap())
+                })
+                .collect(),
+            position: 0,
+        }
+    }
+}
+
+impl crate::encoder::AnalogInput<u8> for MockAdc {
+    fn read(&mut self, channel: &u8) -> u16 {
+        let (clk, dt) = self.samples[self.position.min(self.samples.len() - 1)];
+        match channel {
+            0 => clk,
+            _ => {
+                // DT is read after CLK, move on to the next sample
+                self.position += 1;
+                dt
+            }
+        }
+    }
+}
+
+/// In-memory EEPROM, erased to `0xFF` like a fresh chip, counting writes.
+pub struct MockEeprom {
+    data: [u8; 1024],
+    writes: usize,
+    wear: [u32; 1024],
+}
+
+impl MockEeprom {
+    pub fn new() -> Self {
+        MockEeprom {
+            data: [0xFF; 1024],
+            writes: 0,
+            wear: [0; 1024],
+        }
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    /// Writes to the most written cell.
+    pub fn max_wear(&self) -> u32 {
+        self.wear.iter().copied().max().unwrap_or(0)
+    }
+}
+
+impl crate::eeprom::Eeprom for MockEeprom {
+    const CAPACITY: u16 = 1024;
+
+    fn read_byte(&mut self, address: u16) -> u8 {
+        self.data[address as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.data[address as usize] = value;
+        self.writes += 1;
+        self.wear[address as usize] += 1;
+    }
+}
+
+/// Collects what the firmware writes to the serial port.
+#[derive(Default)]
+pub struct MockSerial(pub std::string::String);
+
+impl ufmt::uWrite for MockSerial {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        self.0.push_str(s);
+        Ok(())
+    }
+}
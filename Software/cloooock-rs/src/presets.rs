@@ -0,0 +1,169 @@
+use crate::display::{Displayable, GLYPH_A, GLYPH_BLANK, GLYPH_D, GLYPH_L, GLYPH_S};
+use crate::eeprom::Eeprom;
+use crate::settings::{Autosave, Settings, RECORD_CAPACITY};
+
+pub const PRESET_SLOTS: u8 = 8;
+/// First preset record, the current settings follow the presets.
+const ADDRESS: u16 = 0;
+
+fn address(slot: u8) -> u16 {
+    ADDRESS + slot as u16 * RECORD_CAPACITY as u16
+}
+
+/// The preset in `slot`, `None` when it was never saved or its CRC fails.
+pub fn load(eeprom: &mut impl Eeprom, slot: u8) -> Option<Settings> {
+    Settings::read(eeprom, address(slot))
+}
+
+/// Queues `settings` for `slot`, written over the next main loop iterations
+/// by [Autosave::poll]. The CRC at the end of the record goes last, so a save
+/// cut short by a power loss leaves a slot that [load] rejects instead of a
+/// mix of two presets. Presets are saved by hand, rarely enough to do
+/// without the wear leveling of [crate::storage::Ring].
+pub fn save(autosave: &mut Autosave, slot: u8, settings: &Settings) {
+    autosave.write_at(address(slot), *settings);
+}
+
+/// The next saved preset from `slot` in the direction of `change`, wrapping
+/// around. `None` without a change.
+pub fn next_saved(eeprom: &mut impl Eeprom, slot: u8, change: i8) -> Option<(u8, Settings)> {
+    if change == 0 {
+        return None;
+    }
+    let direction = change.signum();
+    let mut next = slot as i8;
+    for _ in 0..PRESET_SLOTS {
+        next = (next + direction).rem_euclid(PRESET_SLOTS as i8);
+        if let Some(settings) = load(eeprom, next as u8) {
+            return Some((next as u8, settings));
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PresetAction {
+    Load,
+    Save,
+}
+
+/// What the preset menu is pointing at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PresetChoice {
+    pub action: PresetAction,
+    pub slot: u8,
+}
+
+impl PresetChoice {
+    pub const fn new(action: PresetAction, slot: u8) -> Self {
+        PresetChoice { action, slot }
+    }
+
+    /// Moves through loading every slot, then saving every slot, wrapping
+    /// around.
+    pub fn step(self, change: i8) -> Self {
+        let index = match self.action {
+            PresetAction::Load => self.slot as i16,
+            PresetAction::Save => (PRESET_SLOTS + self.slot) as i16,
+        };
+        let index = (index + change as i16).rem_euclid(2 * PRESET_SLOTS as i16) as u8;
+        if index < PRESET_SLOTS {
+            PresetChoice::new(PresetAction::Load, index)
+        } else {
+            PresetChoice::new(PresetAction::Save, index - PRESET_SLOTS)
+        }
+    }
+}
+
+impl Displayable for PresetChoice {
+    /// `Ld 1` or `SA 1`, slots count from one on the panel.
+    fn display_digit(&self, index: u8) -> u8 {
+        match (self.action, index) {
+            (PresetAction::Load, 0) => GLYPH_L,
+            (PresetAction::Load, 1) => GLYPH_D,
+            (PresetAction::Save, 0) => GLYPH_S,
+            (PresetAction::Save, 1) => GLYPH_A,
+            (_, 2) => GLYPH_BLANK,
+            _ => (self.slot as u16 + 1).display_digit(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEeprom;
+    use crate::time::BPM;
+
+    fn save_now(eeprom: &mut MockEeprom, slot: u8, settings: &Settings) {
+        let mut autosave = Autosave::new(Settings::default());
+        save(&mut autosave, slot, settings);
+        while autosave.is_writing() {
+            autosave.poll(Settings::default(), 0, eeprom);
+        }
+    }
+
+    #[test]
+    fn slots_do_not_overlap() {
+        let mut eeprom = MockEeprom::new();
+        Settings::default().save(&mut eeprom);
+        for slot in 0..PRESET_SLOTS {
+            let settings = Settings {
+                bpm: BPM::new(100 + slot as u16),
+                ..Settings::default()
+            };
+            save_now(&mut eeprom, slot, &settings);
+        }
+        for slot in 0..PRESET_SLOTS {
+            assert_eq!(
+                load(&mut eeprom, slot).unwrap().bpm,
+                BPM::new(100 + slot as u16)
+            );
+        }
+        assert_eq!(Settings::load(&mut eeprom), Settings::default());
+        assert!(address(PRESET_SLOTS) <= crate::settings::STORE.base());
+    }
+
+    #[test]
+    fn next_saved_skips_empty_slots() {
+        let mut eeprom = MockEeprom::new();
+        assert_eq!(next_saved(&mut eeprom, 0, 1), None);
+        save_now(&mut eeprom, 2, &Settings::default());
+        save_now(&mut eeprom, 5, &Settings::default());
+        assert_eq!(next_saved(&mut eeprom, 2, 1).unwrap().0, 5);
+        assert_eq!(next_saved(&mut eeprom, 5, 1).unwrap().0, 2);
+        assert_eq!(next_saved(&mut eeprom, 2, -1).unwrap().0, 5);
+        assert_eq!(next_saved(&mut eeprom, 2, 0), None);
+    }
+
+    #[test]
+    fn interrupted_save_is_not_loaded() {
+        let mut eeprom = MockEeprom::new();
+        save_now(&mut eeprom, 1, &Settings::default());
+        save_now(&mut eeprom, 3, &Settings::default());
+        let mut autosave = Autosave::new(Settings::default());
+        let mut settings = Settings {
+            bpm: BPM::new(90),
+            ..Settings::default()
+        };
+        settings.channels[3].denominator = 16;
+        save(&mut autosave, 3, &settings);
+        // power lost after the new BPM, before the channels and the CRC
+        let writes = eeprom.writes();
+        while eeprom.writes() == writes {
+            autosave.poll(Settings::default(), 0, &mut eeprom);
+        }
+        assert!(autosave.is_writing());
+        assert_eq!(load(&mut eeprom, 3), None);
+        assert_eq!(next_saved(&mut eeprom, 1, 1).unwrap().0, 1);
+    }
+
+    #[test]
+    fn choice_steps_through_load_then_save() {
+        let first = PresetChoice::new(PresetAction::Load, 0);
+        assert_eq!(first.step(-1), PresetChoice::new(PresetAction::Save, 7));
+        assert_eq!(first.step(9), PresetChoice::new(PresetAction::Save, 1));
+        let digits = [0, 1, 2, 3].map(|index| first.step(9).display_digit(index));
+        assert_eq!(digits, [GLYPH_S, GLYPH_A, GLYPH_BLANK, 2]);
+    }
+}
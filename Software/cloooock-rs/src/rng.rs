@@ -0,0 +1,56 @@
+/// Small xorshift pseudo random generator, cheap enough for the AVR and
+/// repeatable for a given seed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// Any seed works, similar seeds still give unrelated sequences.
+    pub const fn new(seed: u32) -> Self {
+        // xorshift never leaves zero, scramble the seed and avoid it
+        let state = (seed ^ 0x2545_F491).wrapping_mul(0x9E37_79B9);
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// `true` with a chance of `percent` out of 100.
+    pub fn chance(&mut self, percent: u8) -> bool {
+        (self.next_u32() % 100) < percent as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_repeats_the_sequence() {
+        let (mut a, mut b, mut c) = (Rng::new(7), Rng::new(7), Rng::new(8));
+        let a: Vec<u32> = (0..8).map(|_| a.next_u32()).collect();
+        let b: Vec<u32> = (0..8).map(|_| b.next_u32()).collect();
+        let c: Vec<u32> = (0..8).map(|_| c.next_u32()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(Rng::new(0x2545_F491).next_u32(), 0);
+    }
+
+    #[test]
+    fn chance_follows_the_percentage() {
+        let mut rng = Rng::new(1);
+        let hits = (0..10_000).filter(|_| rng.chance(30)).count();
+        assert!((2800..3200).contains(&hits), "{}", hits);
+        assert!((0..100).all(|_| rng.chance(100)));
+        assert!((0..100).all(|_| !rng.chance(0)));
+    }
+}
@@ -0,0 +1,342 @@
+use core::ops::RangeInclusive;
+use core::str::SplitAsciiWhitespace;
+
+use ufmt::uWrite;
+
+use crate::app::{MAX_BPM, MIN_BPM, NUM_CHANNELS};
+use crate::cv_output::MAX_RATIO;
+use crate::state_machine::DeviceState;
+use crate::time::BPM;
+
+/// Longest command line, longer lines are rejected whole.
+pub const MAX_LINE: usize = 32;
+
+/// A request received over the serial port, one per line, e.g. `bpm 128`.
+/// Channels count from one, like the LEDs on the panel.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// `bpm <bpm>`, with up to one decimal, e.g. `bpm 127.5`.
+    Bpm(BPM),
+    /// `div <channel> <denominator>`, the channel edges `denominator` times
+    /// per `numerator` bars. Typed from one, `channel` is stored from zero.
+    Div { channel: usize, denominator: u16 },
+    /// `num <channel> <numerator>`, the channel edges `denominator` times per
+    /// `numerator` bars, so a larger numerator slows it down. Typed from one,
+    /// `channel` is stored from zero.
+    Num { channel: usize, numerator: u16 },
+    /// `pause`
+    Pause,
+    /// `run`, also leaves the menus.
+    Run,
+    /// `reset`, restarts the bar like the reset input.
+    Reset,
+    /// `get state`
+    GetState,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    Unknown,
+    /// Missing, extra or malformed arguments.
+    Syntax,
+    OutOfRange,
+    TooLong,
+}
+
+impl Error {
+    fn message(self) -> &'static str {
+        match self {
+            Error::Unknown => "unknown command",
+            Error::Syntax => "syntax",
+            Error::OutOfRange => "out of range",
+            Error::TooLong => "line too long",
+        }
+    }
+}
+
+/// Parses one line, surrounding whitespace included.
+pub fn parse(line: &str) -> Result<Command, Error> {
+    let mut words = line.split_ascii_whitespace();
+    let command = match words.next().ok_or(Error::Syntax)? {
+        "bpm" => Command::Bpm(bpm(&mut words)?),
+        "div" => Command::Div {
+            channel: channel(&mut words)?,
+            denominator: number(&mut words, 1..=MAX_RATIO)?,
+        },
+        "num" => Command::Num {
+            channel: channel(&mut words)?,
+            numerator: number(&mut words, 1..=MAX_RATIO)?,
+        },
+        "pause" => Command::Pause,
+        "run" => Command::Run,
+        "reset" => Command::Reset,
+        "get" => match words.next() {
+            Some("state") => Command::GetState,
+            _ => return Err(Error::Syntax),
+        },
+        _ => return Err(Error::Unknown),
+    };
+    match words.next() {
+        Some(_) => Err(Error::Syntax),
+        None => Ok(command),
+    }
+}
+
+fn number(words: &mut SplitAsciiWhitespace, range: RangeInclusive<u16>) -> Result<u16, Error> {
+    let value = words
+        .next()
+        .and_then(|word| word.parse().ok())
+        .ok_or(Error::Syntax)?;
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(Error::OutOfRange)
+    }
+}
+
+/// Whole beats per minute, optionally followed by a decimal point and the
+/// tenths.
+fn bpm(words: &mut SplitAsciiWhitespace) -> Result<BPM, Error> {
+    let word = words.next().ok_or(Error::Syntax)?;
+    let (whole, tenth) = word.split_once('.').unwrap_or((word, "0"));
+    let whole: u16 = whole.parse().map_err(|_| Error::Syntax)?;
+    let tenth = match tenth.as_bytes() {
+        [digit @ b'0'..=b'9'] => digit - b'0',
+        _ => return Err(Error::Syntax),
+    };
+    let bpm = BPM::from_tenths(whole as u32 * 10 + tenth as u32);
+    if (MIN_BPM..=MAX_BPM).contains(&bpm) {
+        Ok(bpm)
+    } else {
+        Err(Error::OutOfRange)
+    }
+}
+
+fn channel(words: &mut SplitAsciiWhitespace) -> Result<usize, Error> {
+    Ok(number(words, 1..=NUM_CHANNELS as u16)? as usize - 1)
+}
+
+/// Collects received bytes into lines, `\r`, `\n` or both end a line.
+pub struct LineReader {
+    line: [u8; MAX_LINE],
+    len: usize,
+    /// The current line did not fit, drop it once it ends.
+    overflow: bool,
+}
+
+impl LineReader {
+    pub const fn new() -> Self {
+        LineReader {
+            line: [0; MAX_LINE],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Takes the next received byte, returns the command once a line that
+    /// is not blank ends.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Command, Error>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let len = core::mem::take(&mut self.len);
+                if core::mem::take(&mut self.overflow) {
+                    return Some(Err(Error::TooLong));
+                }
+                match core::str::from_utf8(&self.line[..len]) {
+                    Ok(line) if line.trim().is_empty() => None,
+                    Ok(line) => Some(parse(line)),
+                    Err(_) => Some(Err(Error::Syntax)),
+                }
+            }
+            _ if self.len < MAX_LINE => {
+                self.line[self.len] = byte;
+                self.len += 1;
+                None
+            }
+            _ => {
+                self.overflow = true;
+                None
+            }
+        }
+    }
+}
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What `get state` reports.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Status {
+    pub state: DeviceState,
+    pub bpm: BPM,
+    /// `(numerator, denominator)` of every channel.
+    pub ratios: [(u16, u16); NUM_CHANNELS],
+}
+
+/// The answer to every command, one line each.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reply {
+    /// `ok`
+    Ok,
+    /// `state running bpm 120 1/2 1/4 1/6 1/8`
+    Status(Status),
+    /// `err out of range`
+    Error(Error),
+}
+
+impl Reply {
+    /// Writes the reply followed by `\r\n`.
+    pub fn write<W: uWrite + ?Sized>(&self, serial: &mut W) -> Result<(), W::Error> {
+        match self {
+            Reply::Ok => serial.write_str("ok")?,
+            Reply::Status(status) => {
+                serial.write_str("state ")?;
+                serial.write_str(state_name(status.state))?;
+                serial.write_str(" bpm ")?;
+                write_number(serial, status.bpm.whole())?;
+                if status.bpm.tenth() != 0 {
+                    serial.write_str(".")?;
+                    write_number(serial, status.bpm.tenth() as u16)?;
+                }
+                for &(numerator, denominator) in &status.ratios {
+                    serial.write_str(" ")?;
+                    write_number(serial, numerator)?;
+                    serial.write_str("/")?;
+                    write_number(serial, denominator)?;
+                }
+            }
+            Reply::Error(error) => {
+                serial.write_str("err ")?;
+                serial.write_str(error.message())?;
+            }
+        }
+        serial.write_str("\r\n")
+    }
+}
+
+fn state_name(state: DeviceState) -> &'static str {
+    match state {
+        DeviceState::Running => "running",
+        DeviceState::Paused => "paused",
+        DeviceState::SelectingChannel => "channel",
+        DeviceState::SettingDivisionState => "division",
+        DeviceState::SettingNumeratorState => "numerator",
+        DeviceState::SettingGateState => "gate",
+        DeviceState::SettingPpqnState => "ppqn",
+        DeviceState::SettingBeatsState => "beats",
+        DeviceState::SettingBeatUnitState => "beat unit",
+        DeviceState::SettingModeState => "mode",
+        DeviceState::SettingStepsState => "steps",
+        DeviceState::SettingPulsesState => "pulses",
+        DeviceState::SettingRotationState => "rotation",
+        DeviceState::SettingRatchetState => "ratchet",
+        DeviceState::SettingRatchetEveryState => "ratchet every",
+        DeviceState::SettingSwingState => "swing",
+        DeviceState::SettingProbabilityState => "probability",
+        DeviceState::SettingOffsetState => "offset",
+        DeviceState::PresetState => "preset",
+    }
+}
+
+fn write_number<W: uWrite + ?Sized>(serial: &mut W, value: u16) -> Result<(), W::Error> {
+    let mut digits = [0; 5];
+    let mut start = digits.len();
+    let mut value = value;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
+    }
+    // only ASCII digits
+    serial.write_str(core::str::from_utf8(&digits[start..]).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockSerial;
+
+    fn read_line(text: &str) -> Option<Result<Command, Error>> {
+        let mut reader = LineReader::new();
+        text.bytes().filter_map(|byte| reader.push(byte)).last()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("bpm 128"), Ok(Command::Bpm(BPM::new(128))));
+        assert_eq!(parse("bpm 127.5"), Ok(Command::Bpm(BPM::from_tenths(1275))));
+        assert_eq!(
+            parse("  div 2 16 "),
+            Ok(Command::Div {
+                channel: 1,
+                denominator: 16
+            })
+        );
+        assert_eq!(
+            parse("num 4 3"),
+            Ok(Command::Num {
+                channel: 3,
+                numerator: 3
+            })
+        );
+        assert_eq!(parse("pause"), Ok(Command::Pause));
+        assert_eq!(parse("reset"), Ok(Command::Reset));
+        assert_eq!(parse("get  state"), Ok(Command::GetState));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(parse("tempo 128"), Err(Error::Unknown));
+        assert_eq!(parse("bpm"), Err(Error::Syntax));
+        assert_eq!(parse("bpm fast"), Err(Error::Syntax));
+        assert_eq!(parse("bpm 128 64"), Err(Error::Syntax));
+        assert_eq!(parse("bpm 10"), Err(Error::OutOfRange));
+        assert_eq!(parse("bpm 29.9"), Err(Error::OutOfRange));
+        assert_eq!(parse("bpm 127.25"), Err(Error::Syntax));
+        assert_eq!(parse("bpm 127."), Err(Error::Syntax));
+        assert_eq!(parse("div 5 2"), Err(Error::OutOfRange));
+        assert_eq!(parse("div 1 0"), Err(Error::OutOfRange));
+        assert_eq!(parse("get"), Err(Error::Syntax));
+    }
+
+    #[test]
+    fn reads_lines() {
+        assert_eq!(
+            read_line("bpm 90\r\n"),
+            Some(Ok(Command::Bpm(BPM::new(90))))
+        );
+        assert_eq!(read_line("\r\n  \n"), None);
+        assert_eq!(read_line("pause"), None);
+        let long = "bpm 1000000000000000000000000000000000\nbpm 90\n";
+        let mut reader = LineReader::new();
+        let replies: Vec<_> = long.bytes().filter_map(|byte| reader.push(byte)).collect();
+        assert_eq!(
+            replies,
+            [Err(Error::TooLong), Ok(Command::Bpm(BPM::new(90)))]
+        );
+    }
+
+    #[test]
+    fn writes_replies() {
+        let mut serial = MockSerial::default();
+        Reply::Ok.write(&mut serial).unwrap();
+        Reply::Error(Error::OutOfRange).write(&mut serial).unwrap();
+        Reply::Status(Status {
+            state: DeviceState::Paused,
+            bpm: BPM::from_tenths(1275),
+            ratios: [(1, 2), (1, 4), (3, 16), (1, 128)],
+        })
+        .write(&mut serial)
+        .unwrap();
+        assert_eq!(
+            serial.0,
+            "ok\r\nerr out of range\r\nstate paused bpm 127.5 1/2 1/4 3/16 1/128\r\n"
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use crate::app::{MAX_BPM, MIN_BPM, NUM_CHANNELS};
+use crate::cv_output::{
+    ChannelMode, GateMode, Ratchet, DEFAULT_RATCHET, MAX_DUTY_PERCENT, MAX_OFFSET_PERCENT,
+    MAX_PROBABILITY_PERCENT, MAX_RATIO, MAX_SWING_PERCENT, MAX_TRIGGER_MS, MIN_SWING_PERCENT,
+};
+use crate::eeprom::Eeprom;
+use crate::euclid::{self, Euclid, MAX_STEPS};
+use crate::storage::{crc16, Ring, SLOT_OVERHEAD};
+use crate::time::{TimeSignature, BPM, DEFAULT_TIME_SIGNATURE, TICK_RATE};
+
+/// Marks a settings record, `CK`.
+pub const MAGIC: [u8; 2] = *b"CK";
+/// Layout of the payload. Bump it whenever the payload changes and teach
+/// [migrate] to read the previous layout.
+pub const SCHEMA_VERSION: u8 = 1;
+/// Unchanged settings this long are written, 3 s.
+pub const SETTLE_TICKS: u32 = 3 * TICK_RATE;
+
+/// Magic, schema version and payload length.
+const HEADER_LEN: usize = 4;
+/// Whole BPM, its tenths, beats per bar and beat unit, ahead of the channels.
+const GLOBALS_LEN: usize = 5;
+/// Numerator, denominator, gate mode kind and value, channel mode, swing,
+/// Euclidean steps, pulses and rotation, probability, offset, ratchet count
+/// and every.
+const CHANNEL_LEN: usize = 13;
+const PAYLOAD_LEN: usize = GLOBALS_LEN + NUM_CHANNELS * CHANNEL_LEN;
+/// Header, payload and CRC.
+pub const RECORD_LEN: usize = HEADER_LEN + PAYLOAD_LEN + 2;
+/// Room reserved for every record in the EEPROM, so the layout stays put
+/// while new settings make the records longer.
+pub const RECORD_CAPACITY: usize = 64;
+const _: () = assert!(RECORD_LEN <= RECORD_CAPACITY);
+/// A ring slot holding one record.
+const SLOT_LEN: usize = RECORD_CAPACITY + SLOT_OVERHEAD;
+/// The current settings rotate through the EEPROM after the presets, see
+/// [crate::presets].
+pub const STORE: Ring = Ring::new(512, RECORD_CAPACITY, 7);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChannelSettings {
+    pub numerator: u16,
+    pub denominator: u16,
+    pub gate_mode: GateMode,
+    pub mode: ChannelMode,
+    /// Percent of a pair of pulses before the second one, 50 is straight.
+    pub swing: u8,
+    /// Pattern played in [ChannelMode::Euclid].
+    pub euclid: Euclid,
+    /// Percent chance of every pulse to fire.
+    pub probability: u8,
+    /// Delay of the pulses, in percent of their period.
+    pub offset: u8,
+    /// Burst played in [ChannelMode::Ratchet].
+    pub ratchet: Ratchet,
+}
+
+impl ChannelSettings {
+    pub const fn new(numerator: u16, denominator: u16) -> Self {
+        ChannelSettings {
+            numerator,
+            denominator,
+            gate_mode: GateMode::Toggle,
+            mode: ChannelMode::Clock,
+            swing: MIN_SWING_PERCENT,
+            euclid: euclid::DEFAULT,
+            probability: MAX_PROBABILITY_PERCENT,
+            offset: 0,
+            ratchet: DEFAULT_RATCHET,
+        }
+    }
+}
+
+/// Everything the front panel edits that survives a power cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Settings {
+    pub bpm: BPM,
+    pub time_signature: TimeSignature,
+    pub channels: [ChannelSettings; NUM_CHANNELS],
+}
+
+impl Default for Settings {
+    /// 120 BPM in 2/4 with the channels dividing the bar by 2, 4, 6 and 8.
+    fn default() -> Self {
+        Settings {
+            bpm: BPM::new(120),
+            time_signature: DEFAULT_TIME_SIGNATURE,
+            channels: [
+                ChannelSettings::new(1, 2),
+                ChannelSettings::new(1, 4),
+                ChannelSettings::new(1, 6),
+                ChannelSettings::new(1, 8),
+            ],
+        }
+    }
+}
+
+impl Settings {
+    /// The stored settings, defaults when the EEPROM is blank or corrupt.
+    pub fn load(eeprom: &mut impl Eeprom) -> Self {
+        let mut record = [0; RECORD_CAPACITY];
+        if STORE.read(eeprom, &mut record) {
+            Self::decode(&record).unwrap_or_default()
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Writes the settings at once, see [Autosave] for spreading the writes
+    /// over the main loop.
+    pub fn save(&self, eeprom: &mut impl Eeprom) {
+        STORE.write(eeprom, &self.padded(), &mut [0; SLOT_LEN]);
+    }
+
+    /// The record at `address`, if there is a valid one.
+    pub fn read(eeprom: &mut impl Eeprom, address: u16) -> Option<Self> {
+        let mut record = [0; RECORD_CAPACITY];
+        eeprom.read(address, &mut record);
+        Self::decode(&record)
+    }
+
+    /// The record followed by erased bytes up to [RECORD_CAPACITY].
+    fn padded(&self) -> [u8; RECORD_CAPACITY] {
+        let mut padded = [0xFF; RECORD_CAPACITY];
+        padded[..RECORD_LEN].copy_from_slice(&self.encode());
+        padded
+    }
+
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut record = [0; RECORD_LEN];
+        record[..2].copy_from_slice(&MAGIC);
+        record[2] = SCHEMA_VERSION;
+        record[3] = PAYLOAD_LEN as u8;
+        let payload = &mut record[HEADER_LEN..HEADER_LEN + PAYLOAD_LEN];
+        payload[..2].copy_from_slice(&self.bpm.whole().to_le_bytes());
+        payload[2] = self.bpm.tenth();
+        payload[3] = self.time_signature.beats();
+        payload[4] = self.time_signature.unit();
+        for (channel, bytes) in self
+            .channels
+            .iter()
+            .zip(payload[GLOBALS_LEN..].chunks_exact_mut(CHANNEL_LEN))
+        {
+            let (kind, value) = match channel.gate_mode {
+                GateMode::Toggle => (0, 0),
+                GateMode::Trigger(ms) => (1, ms),
+                GateMode::Duty(percent) => (2, percent),
+            };
+            let mode = match channel.mode {
+                ChannelMode::Clock => 0,
+                ChannelMode::ResetOut => 1,
+                ChannelMode::Euclid => 2,
+                ChannelMode::Ratchet => 3,
+            };
+            bytes.copy_from_slice(&[
+                channel.numerator as u8,
+                channel.denominator as u8,
+                kind,
+                value,
+                mode,
+                channel.swing,
+                channel.euclid.steps(),
+                channel.euclid.pulses(),
+                channel.euclid.rotation(),
+                channel.probability,
+                channel.offset,
+                channel.ratchet.count(),
+                channel.ratchet.every(),
+            ]);
+        }
+        let crc = crc16(&record[..HEADER_LEN + PAYLOAD_LEN]);
+        record[HEADER_LEN + PAYLOAD_LEN..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    /// Checks and decodes a record of any known schema version.
+    pub fn decode(record: &[u8]) -> Option<Self> {
+        if record.len() < HEADER_LEN || record[..2] != MAGIC {
+            return None;
+        }
+        let version = record[2];
+        let end = HEADER_LEN + record[3] as usize;
+        if record.len() < end + 2 {
+            return None;
+        }
+        let crc = u16::from_le_bytes([record[end], record[end + 1]]);
+        if crc != crc16(&record[..end]) {
+            return None;
+        }
+        migrate(version, &record[HEADER_LEN..end])
+    }
+}
+
+/// Reads the payload of schema `version`. Unknown versions, e.g. from newer
+/// firmware, are rejected.
+fn migrate(version: u8, payload: &[u8]) -> Option<Settings> {
+    match version {
+        SCHEMA_VERSION => decode_payload(payload),
+        _ => None,
+    }
+}
+
+fn decode_payload(payload: &[u8]) -> Option<Settings> {
+    if payload.len() != PAYLOAD_LEN {
+        return None;
+    }
+    let whole = u16::from_le_bytes([payload[0], payload[1]]);
+    let tenth = payload[2];
+    if tenth > 9 {
+        return None;
+    }
+    let mut settings = Settings {
+        bpm: BPM::from_tenths(whole as u32 * 10 + tenth as u32),
+        time_signature: TimeSignature::try_new(payload[3], payload[4])?,
+        ..Settings::default()
+    };
+    if !(MIN_BPM..=MAX_BPM).contains(&settings.bpm) {
+        return None;
+    }
+    let ratio = |value: u8| Some(value as u16).filter(|value| (1..=MAX_RATIO).contains(value));
+    for (channel, bytes) in settings
+        .channels
+        .iter_mut()
+        .zip(payload[GLOBALS_LEN..].chunks_exact(CHANNEL_LEN))
+    {
+        channel.numerator = ratio(bytes[0])?;
+        channel.denominator = ratio(bytes[1])?;
+        channel.gate_mode = match (bytes[2], bytes[3]) {
+            (0, _) => GateMode::Toggle,
+            (1, ms @ 1..=MAX_TRIGGER_MS) => GateMode::Trigger(ms),
+            (2, percent @ 1..=MAX_DUTY_PERCENT) => GateMode::Duty(percent),
+            _ => return None,
+        };
+        channel.mode = match bytes[4] {
+            0 => ChannelMode::Clock,
+            1 => ChannelMode::ResetOut,
+            2 => ChannelMode::Euclid,
+            3 => ChannelMode::Ratchet,
+            _ => return None,
+        };
+        channel.swing = Some(bytes[5])
+            .filter(|swing| (MIN_SWING_PERCENT..=MAX_SWING_PERCENT).contains(swing))?;
+        let (steps, pulses, rotation) = (bytes[6], bytes[7], bytes[8]);
+        if !(1..=MAX_STEPS).contains(&steps) || pulses > steps || rotation >= steps {
+            return None;
+        }
+        channel.euclid = Euclid::new(steps, pulses, rotation);
+        channel.probability =
+            Some(bytes[9]).filter(|&percent| percent <= MAX_PROBABILITY_PERCENT)?;
+        channel.offset = Some(bytes[10]).filter(|&percent| percent <= MAX_OFFSET_PERCENT)?;
+        channel.ratchet = Ratchet::try_new(bytes[11], bytes[12])?;
+    }
+    Some(settings)
+}
+
+/// Saves the settings once they stopped changing for [SETTLE_TICKS], one
+/// byte per call so the main loop never waits for the EEPROM.
+pub struct Autosave {
+    saved: Settings,
+    last: Settings,
+    changed_at: Option<u32>,
+    slot: [u8; SLOT_LEN],
+    address: u16,
+    /// Next byte of `slot` to write, `None` when idle.
+    position: Option<usize>,
+    /// Bytes of `slot` to write.
+    len: usize,
+    /// A record waiting for the one in progress, see [Self::write_at].
+    queued: Option<(u16, Settings)>,
+}
+
+impl Autosave {
+    /// `saved` is what the EEPROM holds, usually from [Settings::load].
+    pub fn new(saved: Settings) -> Self {
+        Autosave {
+            saved,
+            last: saved,
+            changed_at: None,
+            slot: [0; SLOT_LEN],
+            address: 0,
+            position: None,
+            len: SLOT_LEN,
+            queued: None,
+        }
+    }
+
+    /// Queues `settings` as the record at `address`, e.g. a preset, written
+    /// by [Self::poll] one byte at a time like the current settings.
+    pub fn write_at(&mut self, address: u16, settings: Settings) {
+        self.queued = Some((address, settings));
+    }
+
+    /// Call on every main loop iteration with the `current` settings.
+    pub fn poll(&mut self, current: Settings, now: u32, eeprom: &mut impl Eeprom) {
+        if current != self.last {
+            self.last = current;
+            self.changed_at = Some(now);
+        }
+        if let Some(position) = self.position {
+            if eeprom.is_ready() {
+                self.write_from(position, eeprom);
+            }
+            return;
+        }
+        if let Some((address, settings)) = self.queued.take() {
+            self.slot[..RECORD_LEN].copy_from_slice(&settings.encode());
+            self.address = address;
+            self.len = RECORD_LEN;
+            self.position = Some(0);
+            return;
+        }
+        match self.changed_at {
+            Some(changed_at) if now.wrapping_sub(changed_at) >= SETTLE_TICKS => {
+                self.changed_at = None;
+                if current != self.saved {
+                    self.saved = current;
+                    self.address = STORE.prepare(eeprom, &current.padded(), &mut self.slot);
+                    self.len = SLOT_LEN;
+                    self.position = Some(0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether a record is still being written or queued.
+    pub fn is_writing(&self) -> bool {
+        self.position.is_some() || self.queued.is_some()
+    }
+
+    /// Writes the next byte that differs from the EEPROM, starting at
+    /// `position`.
+    fn write_from(&mut self, position: usize, eeprom: &mut impl Eeprom) {
+        for position in position..self.len {
+            let address = self.address + position as u16;
+            if eeprom.read_byte(address) != self.slot[position] {
+                eeprom.write_byte(address, self.slot[position]);
+                self.position = Some(position + 1);
+                return;
+            }
+        }
+        self.position = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEeprom;
+
+    fn edited() -> Settings {
+        let mut settings = Settings {
+            bpm: BPM::from_tenths(1745),
+            time_signature: TimeSignature::try_new(7, 8).unwrap(),
+            ..Settings::default()
+        };
+        settings.channels[1] = ChannelSettings {
+            numerator: 3,
+            denominator: 16,
+            gate_mode: GateMode::Duty(25),
+            mode: ChannelMode::Clock,
+            swing: 66,
+            euclid: Euclid::new(16, 5, 2),
+            probability: 70,
+            offset: 25,
+            ratchet: Ratchet::new(4, 2),
+        };
+        settings.channels[2].mode = ChannelMode::Euclid;
+        settings.channels[3].gate_mode = GateMode::Trigger(10);
+        settings.channels[3].mode = ChannelMode::ResetOut;
+        settings
+    }
+
+    #[test]
+    fn blank_eeprom_loads_defaults() {
+        assert_eq!(Settings::load(&mut MockEeprom::new()), Settings::default());
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut eeprom = MockEeprom::new();
+        edited().save(&mut eeprom);
+        assert_eq!(Settings::load(&mut eeprom), edited());
+    }
+
+    #[test]
+    fn corruption_falls_back_to_defaults() {
+        let mut eeprom = MockEeprom::new();
+        edited().save(&mut eeprom);
+        let byte = eeprom.read_byte(512 + 5);
+        eeprom.write_byte(512 + 5, byte ^ 0x01);
+        assert_eq!(Settings::load(&mut eeprom), Settings::default());
+    }
+
+    #[test]
+    fn store_fits_in_the_eeprom() {
+        assert!(STORE.end() <= MockEeprom::CAPACITY);
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_invalid_values() {
+        let mut record = edited().encode();
+        record[2] = SCHEMA_VERSION + 1;
+        let crc = crc16(&record[..RECORD_LEN - 2]);
+        record[RECORD_LEN - 2..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Settings::decode(&record), None);
+
+        let mut settings = edited();
+        settings.channels[0].denominator = 0;
+        assert_eq!(Settings::decode(&settings.encode()), None);
+
+        let mut settings = edited();
+        settings.channels[2].offset = MAX_OFFSET_PERCENT + 1;
+        assert_eq!(Settings::decode(&settings.encode()), None);
+    }
+
+    #[test]
+    fn autosave_waits_for_edits_to_settle() {
+        let mut eeprom = MockEeprom::new();
+        let mut autosave = Autosave::new(Settings::default());
+        let mut settings = Settings::default();
+        for now in 0..SETTLE_TICKS {
+            // still turning the encoder
+            settings.bpm = BPM::new(120 + (now / 1000) as u16);
+            autosave.poll(settings, now, &mut eeprom);
+        }
+        assert_eq!(eeprom.writes(), 0);
+        let mut now = SETTLE_TICKS;
+        while autosave.is_writing() || eeprom.writes() == 0 {
+            autosave.poll(settings, now, &mut eeprom);
+            now += 1;
+        }
+        assert_eq!(Settings::load(&mut eeprom), settings);
+        // nothing changed, nothing written
+        let writes = eeprom.writes();
+        for now in now..now + 2 * SETTLE_TICKS {
+            autosave.poll(settings, now, &mut eeprom);
+        }
+        assert_eq!(eeprom.writes(), writes);
+    }
+
+    #[test]
+    fn autosave_rotates_through_the_store() {
+        let mut eeprom = MockEeprom::new();
+        let mut autosave = Autosave::new(Settings::default());
+        let mut settings = Settings::default();
+        let mut now = 0;
+        for bpm in 100..100 + 2 * 7 {
+            settings.bpm = BPM::new(bpm);
+            autosave.poll(settings, now, &mut eeprom);
+            for _ in 0..SETTLE_TICKS + SLOT_LEN as u32 + 1 {
+                now += 1;
+                autosave.poll(settings, now, &mut eeprom);
+            }
+            assert!(!autosave.is_writing());
+            assert_eq!(Settings::load(&mut eeprom), settings);
+        }
+        // every slot written twice
+        assert!(eeprom.max_wear() <= 2);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::external_clock::ExternalClock;
+use crate::time::TimeSignature;
+
+/// State shared between the interrupt handlers and the main loop. On the
+/// module it lives in a `Mutex` accessed from critical sections, in the
+/// simulator it is owned by the virtual timer.
+pub struct Shared {
+    /// Ticks since the start of the bar.
+    pub ticks: u32,
+    /// Free running ticks since boot, wrapping after about five days.
+    pub timestamp: u32,
+    pub external_clock: ExternalClock,
+    /// Timing clocks received over MIDI, always 24 PPQN.
+    pub midi_clock: ExternalClock,
+    /// A reset edge arrived, the channels restart once the main loop sees it.
+    pub reset_pending: bool,
+}
+
+impl Shared {
+    pub const fn new() -> Self {
+        Shared {
+            ticks: 0,
+            timestamp: 0,
+            external_clock: ExternalClock::new(),
+            midi_clock: ExternalClock::new(),
+            reset_pending: false,
+        }
+    }
+
+    /// The timer interrupt, fired at [crate::time::TICK_RATE].
+    pub fn timer_tick(&mut self) {
+        self.ticks += 1;
+        self.timestamp = self.timestamp.wrapping_add(1);
+    }
+
+    /// The clock input interrupt, fired on every rising edge at the jack.
+    pub fn clock_edge(&mut self) {
+        if self.external_clock.edge(self.timestamp) {
+            self.ticks = 0;
+        }
+    }
+
+    /// A MIDI timing clock arrived, the counterpart of [Self::clock_edge].
+    pub fn midi_clock_edge(&mut self) {
+        if self.midi_clock.edge(self.timestamp) {
+            self.ticks = 0;
+        }
+    }
+
+    /// Both clock inputs count bars of `signature`.
+    pub fn set_time_signature(&mut self, signature: TimeSignature) {
+        self.external_clock.set_time_signature(signature);
+        self.midi_clock.set_time_signature(signature);
+    }
+
+    /// The reset input interrupt, fired on every rising edge at the jack.
+    pub fn reset_edge(&mut self) {
+        self.ticks = 0;
+        self.reset_pending = true;
+        self.external_clock.restart_bar();
+        self.midi_clock.restart_bar();
+    }
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Access to [Shared] from the main loop.
+pub trait SharedAccess {
+    /// Runs `f` with exclusive access to the shared state.
+    fn with_shared<R>(&mut self, f: impl FnOnce(&mut Shared) -> R) -> R;
+}
+
+impl SharedAccess for Shared {
+    fn with_shared<R>(&mut self, f: impl FnOnce(&mut Shared) -> R) -> R {
+        f(self)
+    }
+}
@@ -1,23 +1,79 @@
+/// Front panel gestures, see [crate::button::ButtonEvent].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ButtonPressed {
+    /// Pause button clicked.
     PauseButton,
+    /// Encoder button clicked.
     EncoderButton,
+    /// Pause button pressed twice, restarts the bar and runs.
+    PauseDoubleClick,
+    /// Encoder button held, opens the preset menu or leaves the other menus.
+    EncoderLongPress,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum DeviceState {
     Running,
     Paused,
     SelectingChannel,
     SettingDivisionState,
+    SettingNumeratorState,
+    SettingGateState,
+    SettingPpqnState,
+    /// Beats per bar and note value of a beat, reached from pause like the
+    /// clock input settings.
+    SettingBeatsState,
+    SettingBeatUnitState,
+    SettingModeState,
+    /// Steps, pulses and rotation of a channel in Euclidean mode, skipped
+    /// for the other modes.
+    SettingStepsState,
+    SettingPulsesState,
+    SettingRotationState,
+    /// Pulses per burst and pulses between bursts of a channel in ratchet
+    /// mode, skipped for the other modes.
+    SettingRatchetState,
+    SettingRatchetEveryState,
+    SettingSwingState,
+    SettingProbabilityState,
+    SettingOffsetState,
+    PresetState,
 }
 
 impl DeviceState {
     pub fn transition(self, button: ButtonPressed) -> DeviceState {
         match (self, button) {
+            (_, ButtonPressed::PauseDoubleClick) => DeviceState::Running,
+            (DeviceState::Running, ButtonPressed::EncoderLongPress) => DeviceState::PresetState,
+            (DeviceState::Paused, ButtonPressed::EncoderLongPress) => DeviceState::Paused,
+            (
+                DeviceState::SettingPpqnState
+                | DeviceState::SettingBeatsState
+                | DeviceState::SettingBeatUnitState,
+                ButtonPressed::EncoderLongPress,
+            ) => DeviceState::Paused,
+            (_, ButtonPressed::EncoderLongPress) => DeviceState::Running,
+
             (DeviceState::Running, ButtonPressed::PauseButton) => DeviceState::Paused,
             (DeviceState::Running, ButtonPressed::EncoderButton) => DeviceState::SelectingChannel,
 
             (DeviceState::Paused, ButtonPressed::PauseButton) => DeviceState::Running,
-            (DeviceState::Paused, ButtonPressed::EncoderButton) => DeviceState::SelectingChannel,
+            (DeviceState::Paused, ButtonPressed::EncoderButton) => DeviceState::SettingPpqnState,
+
+            (DeviceState::SettingPpqnState, ButtonPressed::PauseButton) => DeviceState::Paused,
+            (DeviceState::SettingPpqnState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingBeatsState
+            }
+
+            (DeviceState::SettingBeatsState, ButtonPressed::PauseButton) => DeviceState::Paused,
+            (DeviceState::SettingBeatsState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingBeatUnitState
+            }
+
+            (DeviceState::SettingBeatUnitState, ButtonPressed::PauseButton) => DeviceState::Paused,
+            (DeviceState::SettingBeatUnitState, ButtonPressed::EncoderButton) => {
+                DeviceState::SelectingChannel
+            }
 
             (DeviceState::SelectingChannel, ButtonPressed::PauseButton) => DeviceState::Running,
             (DeviceState::SelectingChannel, ButtonPressed::EncoderButton) => {
@@ -26,8 +82,159 @@ impl DeviceState {
 
             (DeviceState::SettingDivisionState, ButtonPressed::PauseButton) => DeviceState::Running,
             (DeviceState::SettingDivisionState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingNumeratorState
+            }
+
+            (DeviceState::SettingNumeratorState, ButtonPressed::PauseButton) => {
+                DeviceState::Running
+            }
+            (DeviceState::SettingNumeratorState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingGateState
+            }
+
+            (DeviceState::SettingGateState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingGateState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingModeState
+            }
+
+            (DeviceState::PresetState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::PresetState, ButtonPressed::EncoderButton) => DeviceState::Running,
+
+            (DeviceState::SettingModeState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingModeState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingStepsState
+            }
+
+            (DeviceState::SettingStepsState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingStepsState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingPulsesState
+            }
+
+            (DeviceState::SettingPulsesState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingPulsesState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingRotationState
+            }
+
+            (DeviceState::SettingRotationState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingRotationState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingRatchetState
+            }
+
+            (DeviceState::SettingRatchetState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingRatchetState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingRatchetEveryState
+            }
+
+            (DeviceState::SettingRatchetEveryState, ButtonPressed::PauseButton) => {
+                DeviceState::Running
+            }
+            (DeviceState::SettingRatchetEveryState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingSwingState
+            }
+
+            (DeviceState::SettingSwingState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingSwingState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingProbabilityState
+            }
+
+            (DeviceState::SettingProbabilityState, ButtonPressed::PauseButton) => {
+                DeviceState::Running
+            }
+            (DeviceState::SettingProbabilityState, ButtonPressed::EncoderButton) => {
+                DeviceState::SettingOffsetState
+            }
+
+            (DeviceState::SettingOffsetState, ButtonPressed::PauseButton) => DeviceState::Running,
+            (DeviceState::SettingOffsetState, ButtonPressed::EncoderButton) => {
                 DeviceState::SelectingChannel
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_button_toggles_running() {
+        let state = DeviceState::Running.transition(ButtonPressed::PauseButton);
+        assert!(matches!(state, DeviceState::Paused));
+        let state = state.transition(ButtonPressed::PauseButton);
+        assert!(matches!(state, DeviceState::Running));
+    }
+
+    #[test]
+    fn clock_input_is_configured_while_paused() {
+        let state = DeviceState::Paused.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingPpqnState));
+        let state = state.transition(ButtonPressed::PauseButton);
+        assert!(matches!(state, DeviceState::Paused));
+        let state = state
+            .transition(ButtonPressed::EncoderButton)
+            .transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingBeatsState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingBeatUnitState));
+        let state = state.transition(ButtonPressed::EncoderLongPress);
+        assert!(matches!(state, DeviceState::Paused));
+        let state = (0..4).fold(state, |state, _| {
+            state.transition(ButtonPressed::EncoderButton)
+        });
+        assert!(matches!(state, DeviceState::SelectingChannel));
+    }
+
+    #[test]
+    fn gestures_leave_the_menus() {
+        let state = DeviceState::SettingGateState.transition(ButtonPressed::EncoderLongPress);
+        assert!(matches!(state, DeviceState::Running));
+        let state = DeviceState::SettingPpqnState.transition(ButtonPressed::EncoderLongPress);
+        assert!(matches!(state, DeviceState::Paused));
+        let state = DeviceState::Paused.transition(ButtonPressed::PauseDoubleClick);
+        assert!(matches!(state, DeviceState::Running));
+    }
+
+    #[test]
+    fn long_press_opens_the_preset_menu() {
+        let state = DeviceState::Running.transition(ButtonPressed::EncoderLongPress);
+        assert!(matches!(state, DeviceState::PresetState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::Running));
+        let state = DeviceState::PresetState.transition(ButtonPressed::PauseButton);
+        assert!(matches!(state, DeviceState::Running));
+    }
+
+    #[test]
+    fn encoder_button_cycles_channel_editing() {
+        let state = DeviceState::Running.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SelectingChannel));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingDivisionState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingNumeratorState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingGateState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingModeState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingStepsState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingPulsesState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingRotationState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingRatchetState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingRatchetEveryState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingSwingState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingProbabilityState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SettingOffsetState));
+        let state = state.transition(ButtonPressed::EncoderButton);
+        assert!(matches!(state, DeviceState::SelectingChannel));
+        let state = state.transition(ButtonPressed::PauseButton);
+        assert!(matches!(state, DeviceState::Running));
+    }
+}
@@ -0,0 +1,73 @@
+use crate::byte_queue::ByteQueue;
+
+/// Capacity of [StepQueue], far more detents than a busy main loop misses.
+pub const CAPACITY: usize = 16;
+
+/// Encoder detents the interrupt pushes and the main loop pops.
+pub struct StepQueue(ByteQueue<CAPACITY>);
+
+impl StepQueue {
+    pub const fn new() -> Self {
+        StepQueue(ByteQueue::new())
+    }
+
+    /// Called from the interrupt, drops the step if the queue is full.
+    pub fn push(&self, step: i8) -> bool {
+        self.0.push(step as u8)
+    }
+
+    pub fn pop(&self) -> Option<i8> {
+        self.0.pop().map(|step| step as i8)
+    }
+
+    /// Pops every queued step, summed up as one input for the main loop.
+    pub fn drain(&self) -> Option<i8> {
+        let mut total = None;
+        while let Some(step) = self.pop() {
+            total = Some(total.unwrap_or(0i8).saturating_add(step));
+        }
+        total
+    }
+}
+
+impl Default for StepQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_in_order() {
+        let queue = StepQueue::new();
+        assert!(queue.push(1));
+        assert!(queue.push(-1));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(-1));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn drops_steps_when_full() {
+        let queue = StepQueue::new();
+        for _ in 0..CAPACITY - 1 {
+            assert!(queue.push(1));
+        }
+        assert!(!queue.push(1));
+        assert_eq!(queue.drain(), Some(CAPACITY as i8 - 1));
+        assert_eq!(queue.drain(), None);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let queue = StepQueue::new();
+        for _ in 0..3 * CAPACITY {
+            assert!(queue.push(-1));
+            assert!(queue.push(-1));
+            assert_eq!(queue.drain(), Some(-2));
+        }
+    }
+}
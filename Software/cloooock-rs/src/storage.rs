@@ -0,0 +1,201 @@
+use crate::eeprom::Eeprom;
+
+/// Sequence number and CRC around the data of every slot.
+pub const SLOT_OVERHEAD: usize = 4;
+
+/// Spreads the writes of one record over a ring of slots so no EEPROM cell
+/// wears out much faster than the others, each cell is good for about 100k
+/// writes.
+///
+/// A slot holds a sequence number, the data and a CRC over both, in this
+/// order. Every write goes to the slot after the newest one with the next
+/// sequence number and the CRC written last, so a write cut short by a power
+/// loss leaves an invalid slot and the previous record is recovered instead.
+#[derive(Clone, Copy, Debug)]
+pub struct Ring {
+    base: u16,
+    data_len: usize,
+    slots: u16,
+}
+
+impl Ring {
+    /// `slots` slots for `data_len` bytes each, starting at `base`.
+    pub const fn new(base: u16, data_len: usize, slots: u16) -> Self {
+        Ring {
+            base,
+            data_len,
+            slots,
+        }
+    }
+
+    /// First address of the ring.
+    pub const fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub const fn slot_len(&self) -> usize {
+        self.data_len + SLOT_OVERHEAD
+    }
+
+    /// First address after the ring.
+    pub const fn end(&self) -> u16 {
+        self.base + self.slots * self.slot_len() as u16
+    }
+
+    fn address(&self, index: u16) -> u16 {
+        self.base + index * self.slot_len() as u16
+    }
+
+    /// Index and sequence number of the newest valid slot, `None` when the
+    /// ring was never written.
+    pub fn newest(&self, eeprom: &mut impl Eeprom) -> Option<(u16, u16)> {
+        let mut newest: Option<(u16, u16)> = None;
+        for index in 0..self.slots {
+            if let Some(sequence) = self.validate(eeprom, index) {
+                match newest {
+                    Some((_, latest)) if !is_newer(sequence, latest) => {}
+                    _ => newest = Some((index, sequence)),
+                }
+            }
+        }
+        newest
+    }
+
+    /// Reads the data of the newest valid slot into `data`, returns whether
+    /// there was one.
+    pub fn read(&self, eeprom: &mut impl Eeprom, data: &mut [u8]) -> bool {
+        match self.newest(eeprom) {
+            Some((index, _)) => {
+                eeprom.read(self.address(index) + 2, &mut data[..self.data_len]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fills `slot` with the bytes to write for `data` and returns where they
+    /// go. Lets the caller write them at its own pace, first to last.
+    pub fn prepare(&self, eeprom: &mut impl Eeprom, data: &[u8], slot: &mut [u8]) -> u16 {
+        let (index, sequence) = match self.newest(eeprom) {
+            Some((index, sequence)) => ((index + 1) % self.slots, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let end = 2 + self.data_len;
+        slot[..2].copy_from_slice(&sequence.to_le_bytes());
+        slot[2..end].copy_from_slice(&data[..self.data_len]);
+        let crc = crc16(&slot[..end]);
+        slot[end..end + 2].copy_from_slice(&crc.to_le_bytes());
+        self.address(index)
+    }
+
+    /// Writes `data` at once, `slot` is scratch space of [Self::slot_len].
+    pub fn write(&self, eeprom: &mut impl Eeprom, data: &[u8], slot: &mut [u8]) {
+        let address = self.prepare(eeprom, data, slot);
+        eeprom.update(address, &slot[..self.slot_len()]);
+    }
+
+    /// The sequence number of slot `index` if its CRC matches.
+    fn validate(&self, eeprom: &mut impl Eeprom, index: u16) -> Option<u16> {
+        let address = self.address(index);
+        let end = address + 2 + self.data_len as u16;
+        let crc = (address..end).fold(CRC16_INIT, |crc, address| {
+            crc16_update(crc, eeprom.read_byte(address))
+        });
+        let stored = u16::from_le_bytes([eeprom.read_byte(end), eeprom.read_byte(end + 1)]);
+        let sequence =
+            u16::from_le_bytes([eeprom.read_byte(address), eeprom.read_byte(address + 1)]);
+        Some(sequence).filter(|_| crc == stored)
+    }
+}
+
+/// Whether sequence number `a` was written after `b`, allowing for wrap
+/// around.
+fn is_newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+const CRC16_INIT: u16 = 0xFFFF;
+
+fn crc16_update(mut crc: u16, byte: u8) -> u16 {
+    crc ^= (byte as u16) << 8;
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 {
+            (crc << 1) ^ 0x1021
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+/// CRC-16/CCITT-FALSE.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter()
+        .fold(CRC16_INIT, |crc, &byte| crc16_update(crc, byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockEeprom;
+
+    const RING: Ring = Ring::new(100, 6, 8);
+
+    fn write(eeprom: &mut MockEeprom, value: u8) {
+        let mut slot = [0; 10];
+        RING.write(eeprom, &[value; 6], &mut slot);
+    }
+
+    fn read(eeprom: &mut MockEeprom) -> Option<u8> {
+        let mut data = [0; 6];
+        RING.read(eeprom, &mut data).then(|| data[0])
+    }
+
+    #[test]
+    fn recovers_the_newest_record() {
+        let mut eeprom = MockEeprom::new();
+        assert_eq!(read(&mut eeprom), None);
+        for value in 0..20 {
+            write(&mut eeprom, value);
+            assert_eq!(read(&mut eeprom), Some(value));
+        }
+        assert_eq!(RING.end(), 180);
+    }
+
+    #[test]
+    fn spreads_the_writes() {
+        let mut eeprom = MockEeprom::new();
+        for value in 0..80 {
+            write(&mut eeprom, value);
+        }
+        // 80 writes over 8 slots
+        assert_eq!(eeprom.max_wear(), 10);
+    }
+
+    #[test]
+    fn torn_write_recovers_the_previous_record() {
+        let mut eeprom = MockEeprom::new();
+        for value in 0..10 {
+            write(&mut eeprom, value);
+        }
+        let mut slot = [0; 10];
+        let address = RING.prepare(&mut eeprom, &[42; 6], &mut slot);
+        // power lost before the CRC
+        eeprom.update(address, &slot[..8]);
+        assert_eq!(read(&mut eeprom), Some(9));
+        write(&mut eeprom, 43);
+        assert_eq!(read(&mut eeprom), Some(43));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(is_newer(0, u16::MAX));
+        assert!(is_newer(5, 3));
+        assert!(!is_newer(3, 5));
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::time::{BPM, TICK_RATE};
+
+/// Number of tap intervals averaged.
+pub const MAX_INTERVALS: usize = 8;
+/// Intervals needed before a tempo is reported.
+pub const MIN_INTERVALS: usize = 2;
+/// A pause longer than this, 2 s or 30 BPM, starts a new tap sequence.
+const MAX_INTERVAL: u32 = 2 * TICK_RATE;
+/// Taps closer than this, 20 ms, are contact bounce.
+const MIN_INTERVAL: u32 = TICK_RATE / 50;
+/// Intervals off the median by more than 1/OUTLIER_FRACTION are ignored.
+const OUTLIER_FRACTION: u32 = 4;
+
+/// Tempo from taps one beat apart, timed with [crate::shared::Shared::timestamp].
+#[derive(Default)]
+pub struct TapTempo {
+    previous_tap: Option<u32>,
+    /// Most recent intervals, oldest first.
+    intervals: [u32; MAX_INTERVALS],
+    count: usize,
+}
+
+impl TapTempo {
+    pub const fn new() -> Self {
+        TapTempo {
+            previous_tap: None,
+            intervals: [0; MAX_INTERVALS],
+            count: 0,
+        }
+    }
+
+    /// Registers a tap at `now`, returns the tapped tempo once there are
+    /// enough intervals to tell.
+    pub fn tap(&mut self, now: u32) -> Option<BPM> {
+        let previous_tap = self.previous_tap.replace(now);
+        let interval = now.wrapping_sub(previous_tap?);
+        if interval < MIN_INTERVAL {
+            self.previous_tap = previous_tap;
+            return None;
+        }
+        if interval > MAX_INTERVAL {
+            self.count = 0;
+            return None;
+        }
+        if self.count == MAX_INTERVALS {
+            self.intervals.copy_within(1.., 0);
+            self.count -= 1;
+        }
+        self.intervals[self.count] = interval;
+        self.count += 1;
+        self.bpm()
+    }
+
+    /// Averages the intervals close to their median.
+    fn bpm(&self) -> Option<BPM> {
+        if self.count < MIN_INTERVALS {
+            return None;
+        }
+        let mut sorted = self.intervals;
+        let sorted = &mut sorted[..self.count];
+        sorted.sort_unstable();
+        let median = sorted[self.count / 2];
+        let tolerance = median / OUTLIER_FRACTION;
+        let (sum, accepted) = sorted
+            .iter()
+            .filter(|&&interval| interval.abs_diff(median) <= tolerance)
+            .fold((0, 0), |(sum, count), &interval| {
+                (sum + interval, count + 1)
+            });
+        // tenths of beats per minute over the average of the intervals
+        let tenths = (600 * TICK_RATE * accepted + sum / 2) / sum;
+        Some(BPM::from_tenths(tenths))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tap_all(taps: &[u32]) -> Option<u32> {
+        let mut tap_tempo = TapTempo::new();
+        taps.iter()
+            .map(|&now| tap_tempo.tap(now))
+            .last()
+            .flatten()
+            .map(|bpm| bpm.tenths)
+    }
+
+    #[test]
+    fn needs_two_intervals() {
+        assert_eq!(tap_all(&[0, 5000]), None);
+        assert_eq!(tap_all(&[0, 5000, 10_000]), Some(1200));
+    }
+
+    #[test]
+    fn averages_the_intervals() {
+        // 174 BPM is 3448.3 ticks per beat
+        assert_eq!(tap_all(&[0, 3448, 6897, 10_345, 13_793]), Some(1740));
+        // 127.5 BPM is 4705.9 ticks per beat
+        assert_eq!(tap_all(&[0, 4706, 9412, 14_118, 18_824]), Some(1275));
+    }
+
+    #[test]
+    fn rejects_outliers() {
+        assert_eq!(tap_all(&[0, 5000, 10_000, 17_000, 22_000]), Some(1200));
+    }
+
+    #[test]
+    fn long_pause_starts_over() {
+        assert_eq!(tap_all(&[0, 5000, 10_000, 40_000, 44_000]), None);
+        assert_eq!(
+            tap_all(&[0, 5000, 10_000, 40_000, 44_000, 48_000]),
+            Some(1500)
+        );
+    }
+
+    #[test]
+    fn keeps_the_last_intervals() {
+        let mut taps: std::vec::Vec<u32> = (0..10).map(|beat| beat * 5000).collect();
+        let last = *taps.last().unwrap();
+        taps.extend((1..=MAX_INTERVALS as u32).map(|beat| last + beat * 4000));
+        assert_eq!(tap_all(&taps), Some(1500));
+    }
+}
@@ -1,59 +1,172 @@
 use core::ops::Add;
 
-use crate::display::Displayable;
+use crate::display::{Displayable, DECIMAL_POINT, GLYPH_BLANK};
 
 const DEFAULT_BPM: u16 = 120;
-const MAX_BPM: u16 = 10_000;
+/// 10000 BPM in tenths.
+const MAX_TENTHS: u32 = 100_000;
 
-#[derive(Copy, Clone)]
+/// Quarter notes per minute in tenths, 127.5 BPM is 1275.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct BPM {
-    pub bpm: u16,
+    pub tenths: u32,
 }
 
 impl BPM {
+    /// Whole `bpm` beats per minute.
     pub const fn new(bpm: u16) -> Self {
-        BPM { bpm }
+        BPM {
+            tenths: bpm as u32 * 10,
+        }
+    }
+
+    pub const fn from_tenths(tenths: u32) -> Self {
+        BPM { tenths }
+    }
+
+    /// The whole beats per minute, rounded down.
+    pub fn whole(&self) -> u16 {
+        (self.tenths / 10) as u16
+    }
+
+    /// The tenths after the whole beats per minute, `0..=9`.
+    pub fn tenth(&self) -> u8 {
+        (self.tenths % 10) as u8
     }
 }
 
 impl From<u16> for BPM {
     fn from(value: u16) -> Self {
         assert!(value <= 9999);
-        BPM { bpm: value }
+        BPM::new(value)
     }
 }
 
-impl Add<i8> for BPM {
+impl Add<i16> for BPM {
     type Output = Self;
 
-    fn add(self, rhs: i8) -> Self::Output {
-        let intermediate: i16 = self.bpm as i16 + rhs as i16;
-        let bpm: u16 = if intermediate < 0 {
+    /// Adds `rhs` tenths.
+    fn add(self, rhs: i16) -> Self::Output {
+        let intermediate: i64 = self.tenths as i64 + rhs as i64;
+        let tenths: u32 = if intermediate < 0 {
             0
-        } else if intermediate > MAX_BPM as i16 {
-            MAX_BPM
+        } else if intermediate > MAX_TENTHS as i64 {
+            MAX_TENTHS
         } else {
-            intermediate as u16
+            intermediate as u32
         };
-        BPM { bpm }
+        BPM { tenths }
     }
 }
 
 impl Default for BPM {
     /// Default to [DEFAULT_BPM] beats per minute.
     fn default() -> Self {
-        Self { bpm: DEFAULT_BPM }
+        Self::new(DEFAULT_BPM)
     }
 }
 
 impl Displayable for BPM {
+    /// Up to 999.9 with the tenths behind the decimal point, e.g. `127.5`,
+    /// faster tempos in whole beats.
     fn display_digit(&self, index: u8) -> u8 {
-        self.bpm.display_digit(index)
+        if self.tenths >= 10_000 {
+            return self.whole().display_digit(index);
+        }
+        let digits = self.tenths as u16;
+        match index {
+            2 => digits.display_digit(index) | DECIMAL_POINT,
+            _ => digits.display_digit(index),
+        }
     }
 }
 
 pub const TICK_RATE: u32 = 10_000;
 
+/// Most beats in a bar.
+pub const MAX_BEATS: u8 = 16;
+/// Note values a beat can have, a quarter note is 4.
+pub const BEAT_UNITS: [u8; 4] = [2, 4, 8, 16];
+
+/// Beats per bar and the note value of a beat, e.g. 7/8. The tempo always
+/// counts quarter notes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimeSignature {
+    beats: u8,
+    unit: u8,
+}
+
+/// Two quarter notes, the bar length before time signatures were editable.
+pub const DEFAULT_TIME_SIGNATURE: TimeSignature = TimeSignature { beats: 2, unit: 4 };
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        DEFAULT_TIME_SIGNATURE
+    }
+}
+
+impl TimeSignature {
+    /// `None` unless `beats` is within `1..=MAX_BEATS` and `unit` one of
+    /// [BEAT_UNITS].
+    pub fn try_new(beats: u8, unit: u8) -> Option<Self> {
+        let valid = (1..=MAX_BEATS).contains(&beats) && BEAT_UNITS.contains(&unit);
+        valid.then_some(TimeSignature { beats, unit })
+    }
+
+    pub fn beats(&self) -> u8 {
+        self.beats
+    }
+
+    pub fn unit(&self) -> u8 {
+        self.unit
+    }
+
+    /// Pulses in a bar at `ppqn` pulses per quarter note, at least one. Bars
+    /// that are not a whole number of pulses are rounded down.
+    pub const fn pulses_per_bar(&self, ppqn: u32) -> u32 {
+        let pulses = ppqn * 4 * self.beats as u32 / self.unit as u32;
+        if pulses > 0 {
+            pulses
+        } else {
+            1
+        }
+    }
+
+    /// Steps the beats per bar, wrapping around.
+    pub fn step_beats(self, change: i8) -> Self {
+        let beats = (self.beats as i8 - 1 + change).rem_euclid(MAX_BEATS as i8) as u8 + 1;
+        TimeSignature { beats, ..self }
+    }
+
+    /// Steps through [BEAT_UNITS], wrapping around.
+    pub fn step_unit(self, change: i8) -> Self {
+        let index = BEAT_UNITS.iter().position(|&unit| unit == self.unit);
+        let count = BEAT_UNITS.len() as i8;
+        let index = (index.unwrap_or(0) as i8 + change).rem_euclid(count);
+        TimeSignature {
+            unit: BEAT_UNITS[index as usize],
+            ..self
+        }
+    }
+}
+
+impl Displayable for TimeSignature {
+    /// The beats right aligned up to the decimal point, then the unit, e.g.
+    /// ` 7.8 ` or `11.16`.
+    fn display_digit(&self, index: u8) -> u8 {
+        let (beats, unit) = (self.beats, self.unit);
+        match index {
+            0 if beats < 10 => GLYPH_BLANK,
+            0 => beats / 10,
+            1 => (beats % 10) | DECIMAL_POINT,
+            2 if unit < 10 => unit,
+            2 => unit / 10,
+            _ if unit < 10 => GLYPH_BLANK,
+            _ => unit % 10,
+        }
+    }
+}
+
 // Prescaler    Counter Resolution [us]     Counter Overflow [s]
 //---------------------------------------------------------------------
 // 1            0.0625                      0.0040959375
@@ -66,10 +179,80 @@ pub struct TicksPerBar {
     pub ticks: u32,
 }
 
-impl From<BPM> for TicksPerBar {
-    fn from(bpm: BPM) -> Self {
+impl TicksPerBar {
+    /// Length of a bar of `signature` at `bpm` quarter notes per minute.
+    pub fn new(bpm: BPM, signature: TimeSignature) -> Self {
+        let quarters = 4 * signature.beats as u32;
         TicksPerBar {
-            ticks: (240 / 2 * TICK_RATE) / bpm.bpm as u32,
+            ticks: (600 * TICK_RATE * quarters) / (signature.unit as u32 * bpm.tenths),
         }
     }
 }
+
+impl From<BPM> for TicksPerBar {
+    /// A bar of [DEFAULT_TIME_SIGNATURE].
+    fn from(bpm: BPM) -> Self {
+        Self::new(bpm, DEFAULT_TIME_SIGNATURE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_per_bar_follow_bpm() {
+        assert_eq!(TicksPerBar::from(BPM::new(120)).ticks, 10_000);
+        assert_eq!(TicksPerBar::from(BPM::new(60)).ticks, 20_000);
+        assert_eq!(TicksPerBar::from(BPM::from_tenths(1275)).ticks, 9411);
+    }
+
+    #[test]
+    fn time_signature_sets_the_bar_length() {
+        let bar = |beats, unit| TimeSignature::try_new(beats, unit).unwrap();
+        assert_eq!(TicksPerBar::new(BPM::new(120), bar(4, 4)).ticks, 20_000);
+        assert_eq!(TicksPerBar::new(BPM::new(120), bar(7, 8)).ticks, 17_500);
+        assert_eq!(TicksPerBar::new(BPM::new(90), bar(5, 4)).ticks, 33_333);
+        assert_eq!(bar(11, 8).pulses_per_bar(24), 132);
+        assert_eq!(bar(7, 8).pulses_per_bar(1), 3);
+        assert_eq!(TimeSignature::try_new(3, 3), None);
+        assert_eq!(TimeSignature::try_new(0, 4), None);
+    }
+
+    #[test]
+    fn time_signature_steps_wrap_around() {
+        let signature = DEFAULT_TIME_SIGNATURE.step_beats(-2);
+        assert_eq!(signature.beats(), MAX_BEATS);
+        assert_eq!(signature.step_unit(2).unit(), 16);
+        assert_eq!(signature.step_unit(3).unit(), 2);
+    }
+
+    #[test]
+    fn displays_time_signatures() {
+        let digits = |beats, unit| {
+            let signature = TimeSignature::try_new(beats, unit).unwrap();
+            [0, 1, 2, 3].map(|i| signature.display_digit(i))
+        };
+        assert_eq!(
+            digits(7, 8),
+            [GLYPH_BLANK, 7 | DECIMAL_POINT, 8, GLYPH_BLANK]
+        );
+        assert_eq!(digits(11, 16), [1, 1 | DECIMAL_POINT, 1, 6]);
+    }
+
+    #[test]
+    fn add_saturates() {
+        assert_eq!((BPM::new(0) + -1).tenths, 0);
+        assert_eq!((BPM::from_tenths(MAX_TENTHS) + 1).tenths, MAX_TENTHS);
+        assert_eq!((BPM::new(120) + -5), BPM::from_tenths(1195));
+        assert_eq!((BPM::new(9000) + 20_000).tenths, MAX_TENTHS);
+    }
+
+    #[test]
+    fn displays_digits() {
+        let digits = |bpm: BPM| [0, 1, 2, 3].map(|i| bpm.display_digit(i));
+        assert_eq!(digits(BPM::new(174)), [1, 7, 4 | DECIMAL_POINT, 0]);
+        assert_eq!(digits(BPM::from_tenths(933)), [0, 9, 3 | DECIMAL_POINT, 3]);
+        assert_eq!(digits(BPM::from_tenths(12_345)), [1, 2, 3, 4]);
+    }
+}